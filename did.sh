@@ -12,7 +12,7 @@ function generate_did() {
   candid-extractor "target/wasm32-unknown-unknown/release/$canister.wasm" > "$canister_root/$canister.did"
 }
 
-CANISTERS=music_licensing_backend
+CANISTERS=music_licensing_backend,icrc_ledger_stub
 
 for canister in $(echo $CANISTERS | sed "s/,/ /g")
 do
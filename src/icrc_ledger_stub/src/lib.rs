@@ -0,0 +1,280 @@
+#[macro_use]
+extern crate serde;
+use candid::{Nat, Principal};
+use std::{cell::RefCell, collections::BTreeMap};
+
+// Fee charged on every transfer and approval
+const FEE: u64 = 10;
+
+// Accounts are keyed by owner and a normalized 32-byte subaccount
+type AccountKey = (Principal, Vec<u8>);
+
+// Define the ICRC-1 and ICRC-2 types used by the ledger interface
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApproveArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+// Ledger state lives on the heap, the stub is reset on every upgrade
+#[derive(Default)]
+struct Ledger {
+    balances: BTreeMap<AccountKey, Nat>,
+    allowances: BTreeMap<(AccountKey, AccountKey), Nat>,
+    blocks: u64,
+}
+
+thread_local! {
+    static LEDGER: RefCell<Ledger> = RefCell::default();
+}
+
+// Define update function to mint tokens into any account, only for local testing
+#[ic_cdk::update]
+fn mint(to: Account, amount: Nat) -> Nat {
+    LEDGER.with(|l| {
+        let mut ledger = l.borrow_mut();
+        let balance = ledger.balances.entry(key(&to)).or_default();
+        *balance += amount;
+        next_block(&mut ledger)
+    })
+}
+
+// Define query function to get the transfer fee
+#[ic_cdk::query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+// Define query function to get the balance of an account
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    LEDGER.with(|l| balance_of(&l.borrow(), &key(&account)))
+}
+
+// Define update function to transfer tokens from the caller's account
+#[ic_cdk::update]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    if let Some(expected_fee) = bad_fee(&arg.fee) {
+        return Err(TransferError::BadFee { expected_fee });
+    }
+
+    let from = key(&Account {
+        owner: ic_cdk::caller(),
+        subaccount: arg.from_subaccount,
+    });
+
+    LEDGER.with(|l| {
+        let mut ledger = l.borrow_mut();
+        let balance = balance_of(&ledger, &from);
+        if balance < arg.amount.clone() + FEE {
+            return Err(TransferError::InsufficientFunds { balance });
+        }
+
+        move_tokens(&mut ledger, from, key(&arg.to), arg.amount);
+        Ok(next_block(&mut ledger))
+    })
+}
+
+// Define update function to let a spender transfer from the caller's account
+#[ic_cdk::update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    if let Some(expected_fee) = bad_fee(&args.fee) {
+        return Err(ApproveError::BadFee { expected_fee });
+    }
+
+    let from = key(&Account {
+        owner: ic_cdk::caller(),
+        subaccount: args.from_subaccount,
+    });
+    let spender = key(&args.spender);
+
+    LEDGER.with(|l| {
+        let mut ledger = l.borrow_mut();
+        let balance = balance_of(&ledger, &from);
+        if balance < FEE {
+            return Err(ApproveError::InsufficientFunds { balance });
+        }
+
+        let current_allowance = allowance_of(&ledger, &from, &spender);
+        if let Some(expected_allowance) = args.expected_allowance {
+            if expected_allowance != current_allowance {
+                return Err(ApproveError::AllowanceChanged { current_allowance });
+            }
+        }
+
+        // The approval fee is burned
+        ledger.balances.insert(from.clone(), balance - FEE);
+        ledger.allowances.insert((from, spender), args.amount);
+        Ok(next_block(&mut ledger))
+    })
+}
+
+// Define query function to get what a spender may still transfer from an account
+#[ic_cdk::query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    LEDGER.with(|l| Allowance {
+        allowance: allowance_of(&l.borrow(), &key(&args.account), &key(&args.spender)),
+        expires_at: None,
+    })
+}
+
+// Define update function for the caller to spend an allowance
+#[ic_cdk::update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    if let Some(expected_fee) = bad_fee(&args.fee) {
+        return Err(TransferFromError::BadFee { expected_fee });
+    }
+
+    let from = key(&args.from);
+    let spender = key(&Account {
+        owner: ic_cdk::caller(),
+        subaccount: args.spender_subaccount,
+    });
+    let total = args.amount.clone() + FEE;
+
+    LEDGER.with(|l| {
+        let mut ledger = l.borrow_mut();
+        let allowance = allowance_of(&ledger, &from, &spender);
+        if allowance < total {
+            return Err(TransferFromError::InsufficientAllowance { allowance });
+        }
+
+        let balance = balance_of(&ledger, &from);
+        if balance < total {
+            return Err(TransferFromError::InsufficientFunds { balance });
+        }
+
+        ledger.allowances.insert((from.clone(), spender), allowance - total);
+        move_tokens(&mut ledger, from, key(&args.to), args.amount);
+        Ok(next_block(&mut ledger))
+    })
+}
+
+// Helper function to normalize an account into a storage key
+fn key(account: &Account) -> AccountKey {
+    let subaccount = account.subaccount.clone().unwrap_or_else(|| vec![0; 32]);
+    (account.owner, subaccount)
+}
+
+// Helper function to check a caller supplied fee, returns the expected fee when it is wrong
+fn bad_fee(fee: &Option<Nat>) -> Option<Nat> {
+    match fee {
+        Some(fee) if *fee != FEE => Some(Nat::from(FEE)),
+        _ => None,
+    }
+}
+
+// Helper function to get the balance of an account
+fn balance_of(ledger: &Ledger, account: &AccountKey) -> Nat {
+    ledger.balances.get(account).cloned().unwrap_or_default()
+}
+
+// Helper function to get the allowance of a spender on an account
+fn allowance_of(ledger: &Ledger, account: &AccountKey, spender: &AccountKey) -> Nat {
+    ledger
+        .allowances
+        .get(&(account.clone(), spender.clone()))
+        .cloned()
+        .unwrap_or_default()
+}
+
+// Helper function to move tokens between accounts, burning the fee
+fn move_tokens(ledger: &mut Ledger, from: AccountKey, to: AccountKey, amount: Nat) {
+    let balance = balance_of(ledger, &from);
+    ledger.balances.insert(from, balance - amount.clone() - FEE);
+    let balance = ledger.balances.entry(to).or_default();
+    *balance += amount;
+}
+
+// Helper function to allocate the next block index
+fn next_block(ledger: &mut Ledger) -> Nat {
+    let block = ledger.blocks;
+    ledger.blocks += 1;
+    Nat::from(block)
+}
+
+// Candid generator for Candid interface
+ic_cdk::export_candid!();
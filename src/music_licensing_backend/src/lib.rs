@@ -1,979 +1,5665 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
-use validator::Validate;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    thread::LocalKey,
+    time::Duration,
+};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use validator::{Validate, ValidationError, ValidationErrors};
 
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+// Index map whose keys pair a record with a related id
+type IndexMap<K> = StableBTreeMap<K, (), Memory>;
+
+// Define a typed id for an entity, a single field record on the wire so each kind of id has its own
+// Candid type, and a plain u64 in storage keys so existing keys keep their ids
+macro_rules! entity_id {
+    ($name:ident, $field:ident) => {
+        #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+        struct $name(u64);
+
+        impl candid::CandidType for $name {
+            fn _ty() -> candid::types::Type {
+                candid::types::TypeInner::Record(vec![candid::types::Field {
+                    id: candid::types::Label::Named(stringify!($field).to_string()).into(),
+                    ty: u64::ty(),
+                }])
+                .into()
+            }
+            fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+                let mut record = serializer.serialize_struct()?;
+                candid::types::Compound::serialize_element(&mut record, &self.0)
+            }
+        }
+
+        // Serde goes through the same single field record
+        const _: () = {
+            #[derive(Serialize, Deserialize)]
+            struct Record {
+                $field: u64,
+            }
+
+            impl serde::Serialize for $name {
+                fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    Record { $field: self.0 }.serialize(serializer)
+                }
+            }
+
+            impl<'de> serde::Deserialize<'de> for $name {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    Record::deserialize(deserializer).map(|record| $name(record.$field))
+                }
+            }
+        };
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        // Keys keep the layout of the u64 keys they replace
+        impl Storable for $name {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(self.0.to_bytes().into_owned())
+            }
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                $name(u64::from_bytes(bytes))
+            }
+            const BOUND: Bound = u64::BOUND;
+        }
+    };
+}
+
+entity_id!(SongId, song_id);
+entity_id!(WorkId, work_id);
+entity_id!(OwnerId, owner_id);
+entity_id!(LicenseId, license_id);
+entity_id!(LicenseeId, licensee_id);
+entity_id!(TemplateId, template_id);
+
+// Largest encoding of a bounded record, other records hold lists that can grow without limit and are unbounded
+const MAX_RECORD_BYTES: u32 = 1024;
+
+// Longest title, name or email of a bounded record, which keeps it within MAX_RECORD_BYTES at four bytes a character
+const MAX_BOUNDED_TEXT_CHARS: u64 = 100;
+
+// Longest text kept in an index key, longer texts are cut at a character boundary
+const MAX_INDEX_TEXT_BYTES: usize = 128;
+
+// Text used as a key of the genre, artist and search indexes, trimmed, lowercased and cut to a bounded length,
+// lookups compare the full text of each song found since cut keys can be shared
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct IndexText(String);
+
+impl IndexText {
+    fn new(text: &str) -> Self {
+        let mut key = normalize_index_text(text);
+        if key.len() > MAX_INDEX_TEXT_BYTES {
+            let mut end = MAX_INDEX_TEXT_BYTES;
+            while !key.is_char_boundary(end) {
+                end -= 1;
+            }
+            key.truncate(end);
+        }
+        IndexText(key)
+    }
+
+    // A cut key ends on a char boundary at most three bytes short of the bound, so shorter keys are whole texts
+    fn may_be_cut(&self) -> bool {
+        self.0.len() + 3 >= MAX_INDEX_TEXT_BYTES
+    }
+}
+
+// Weight a search term carries for each time it appears in a song field
+const TITLE_TERM_WEIGHT: u32 = 3;
+const ARTIST_TERM_WEIGHT: u32 = 2;
+const GENRE_TERM_WEIGHT: u32 = 1;
+const YEAR_TERM_WEIGHT: u32 = 1;
+const MOOD_TERM_WEIGHT: u32 = 1;
+const ISRC_TERM_WEIGHT: u32 = 3;
+
+// Layout of the terms kept in the search index, raised whenever a change to the indexed fields or to
+// the splitting of terms makes the stored index stale, so the next upgrade rebuilds it
+const SEARCH_INDEX_LAYOUT: u64 = 2;
+
+// Share of the score a search term earns when it only matches the start of an indexed term
+const PREFIX_MATCH_FACTOR: f64 = 0.5;
+
+// Shortest search term matched as a prefix, shorter terms only match whole indexed terms
+const MIN_PREFIX_CHARS: usize = 2;
+
+// Most terms a search query may hold across all its groups
+const MAX_SEARCH_TERMS: usize = 16;
+
+// Most genres a song filter may list
+const MAX_FILTER_GENRES: usize = 16;
+
+// Lowest price of each price band counted by the catalog facets, a band reaches up to the next one
+const PRICE_BAND_FLOORS: [u32; 7] = [0, 100, 250, 500, 1_000, 2_500, 5_000];
+
+// How often the timer looks for approved licenses past their end date
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Number of nanoseconds in a day, license dates are nanosecond timestamps
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+// Most offers a license negotiation may hold
+const MAX_LICENSE_OFFERS: usize = 8;
+
+// How long a license request may wait for the owner before its escrow is refunded
+const LICENSE_REQUEST_TIMEOUT: u64 = 14 * NANOS_PER_DAY;
+
+// Basis points in a whole, used for fee shares
+const BPS_DENOMINATOR: u64 = 10_000;
+
+// Longest license term accepted until a controller configures another one
+const DEFAULT_MAX_LICENSE_DURATION: u64 = 10 * 365 * NANOS_PER_DAY;
+
+// Most rights holders a song may split its revenue between
+const MAX_RIGHTS_HOLDERS: usize = 16;
+
+// Longest recording accepted, in seconds
+const MAX_DURATION_SECS: u32 = 24 * 60 * 60;
+
+// Tempo range accepted, in beats per minute
+const MIN_BPM: u32 = 20;
+const MAX_BPM: u32 = 300;
+
+// Most mood and theme tags a song may carry, and the longest tag
+const MAX_MOOD_TAGS: usize = 10;
+const MAX_MOOD_TAG_CHARS: usize = 32;
+
+// Items returned by a list query when no limit is given, and the largest limit accepted
+const DEFAULT_PAGE_LIMIT: u32 = 50;
+const MAX_PAGE_LIMIT: u32 = 100;
+
+// ISO 3166-1 alpha-2 country codes accepted in license territories
+const ISO_3166_ALPHA_2: &str = "\
+    AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ BA BB BD BE BF BG BH BI BJ BL BM BN BO BQ \
+    BR BS BT BV BW BY BZ CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ DE DJ DK DM \
+    DO DZ EC EE EG EH ER ES ET FI FJ FK FM FO FR GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS \
+    GT GU GW GY HK HM HN HR HT HU ID IE IL IM IN IO IQ IR IS IT JE JM JO JP KE KG KH KI KM KN \
+    KP KR KW KY KZ LA LB LC LI LK LR LS LT LU LV LY MA MC MD ME MF MG MH MK ML MM MN MO MP MQ \
+    MR MS MT MU MV MW MX MY MZ NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF PG PH PK PL PM \
+    PN PR PS PT PW PY QA RE RO RS RU RW SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV \
+    SX SY SZ TC TD TF TG TH TJ TK TL TM TN TO TR TT TV TW TZ UA UG UM US UY UZ VA VC VE VG VI \
+    VN VU WF WS YE YT ZA ZM ZW";
+
+// ISO 639-1 language codes accepted as the language of a song
+const ISO_639_1: &str = "\
+    aa ab ae af ak am an ar as av ay az ba be bg bi bm bn bo br bs ca ce ch co cr cs cu cv cy \
+    da de dv dz ee el en eo es et eu fa ff fi fj fo fr fy ga gd gl gn gu gv ha he hi ho hr ht \
+    hu hy hz ia id ie ig ii ik io is it iu ja jv ka kg ki kj kk kl km kn ko kr ks ku kv kw ky \
+    la lb lg li ln lo lt lu lv mg mh mi mk ml mn mr ms mt my na nb nd ne ng nl nn no nr nv ny \
+    oc oj om or os pa pi pl ps pt qu rm rn ro ru rw sa sc sd se sg si sk sl sm sn so sq sr ss \
+    st su sv sw ta te tg th ti tk tl tn to tr ts tt tw ty ug uk ur uz ve vi vo wa wo xh yi yo \
+    za zh zu";
 
 // Define the data structures that will be stored in the stable memory
+// A song is a master recording, its composition is the work it references
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Song {
-    id: u64,
+    id: SongId,
+    title: String,
+    artist: String,
+    owner_id: OwnerId,
+    year: u32,
+    genre: String,
+    price: u32,
+    // Everyone owed a share of the recording's license revenue, the shares add up to a whole
+    rights_holders: Vec<RightsHolder>,
+    // Share of the rights, in basis points, whose holders must approve a license
+    approval_quorum_bps: u64,
+    // Composition the recording performs, needed to license its composition rights
+    work_id: Option<WorkId>,
+    // International Standard Recording Code, normalized to 12 characters
+    isrc: Option<String>,
+    // Uses, channels and territories the owner offers licenses for
+    availability: SongAvailability,
+    // Length of the recording in seconds
+    duration_secs: Option<u32>,
+    // Tempo in beats per minute
+    bpm: Option<u32>,
+    musical_key: Option<MusicalKey>,
+    // Moods and themes, trimmed, lowercased and sorted
+    mood_tags: Vec<String>,
+    // ISO 639-1 code of the language sung in, None for instrumentals
+    language: Option<String>,
+    // Whether the lyrics are explicit
+    explicit: bool,
+    // Raised by every update, writes made against an older version are refused
+    version: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct MusicalKey {
+    tonic: PitchClass,
+    mode: KeyMode,
+}
+
+// Tonic of a key, enharmonic notes share the sharp name
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum PitchClass {
+    C,
+    CSharp,
+    D,
+    DSharp,
+    E,
+    F,
+    FSharp,
+    G,
+    GSharp,
+    A,
+    ASharp,
+    B,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum KeyMode {
+    Major,
+    Minor,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SongAvailability {
+    usages: Vec<UsageType>,
+    media_channels: Vec<MediaChannel>,
+    territory: Territory,
+    // Whether licensees may ask for exclusive licenses
+    exclusive: bool,
+}
+
+impl SongAvailability {
+    // Everything is available on songs stored before availability existed
+    fn unrestricted() -> Self {
+        SongAvailability {
+            usages: UsageType::ALL.to_vec(),
+            media_channels: MediaChannel::ALL.to_vec(),
+            territory: Territory::Worldwide,
+            exclusive: true,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum UsageType {
+    Sync,
+    Streaming,
+    Broadcast,
+    LivePerformance,
+    Advertising,
+}
+
+impl UsageType {
+    const ALL: [UsageType; 5] = [
+        UsageType::Sync,
+        UsageType::Streaming,
+        UsageType::Broadcast,
+        UsageType::LivePerformance,
+        UsageType::Advertising,
+    ];
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum MediaChannel {
+    Television,
+    Radio,
+    Film,
+    Online,
+    SocialMedia,
+    VideoGame,
+    Venue,
+}
+
+impl MediaChannel {
+    const ALL: [MediaChannel; 7] = [
+        MediaChannel::Television,
+        MediaChannel::Radio,
+        MediaChannel::Film,
+        MediaChannel::Online,
+        MediaChannel::SocialMedia,
+        MediaChannel::VideoGame,
+        MediaChannel::Venue,
+    ];
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq, Eq, Debug)]
+enum Territory {
+    #[default]
+    Worldwide,
+    // ISO 3166-1 alpha-2 country codes, stored uppercase, sorted and without duplicates
+    Countries(Vec<String>),
+}
+
+// A musical composition, licensed for sync, mechanical and performance rights
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Work {
+    id: WorkId,
+    title: String,
+    // International Standard Musical Work Code, normalized to "T" and 10 digits
+    iswc: String,
+    owner_id: OwnerId,
+    // Writers and publishers owed a share of the composition's license revenue
+    rights_holders: Vec<RightsHolder>,
+    approval_quorum_bps: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug)]
+enum LicenseRight {
+    // Use of the master recording, granted by the song's rights holders
+    #[default]
+    Master,
+    // Remaining rights are in the composition, granted by the work's rights holders
+    Sync,
+    Mechanical,
+    Performance,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum RightsRole {
+    // Holds the rights outright, the role of songs added before revenue splits
+    Owner,
+    Writer,
+    Publisher,
+    Producer,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RightsHolder {
+    owner_id: OwnerId,
+    // Share of the song's license revenue in basis points
+    share_bps: u64,
+    role: RightsRole,
+}
+
+// Song layouts stored by earlier versions, fields added since revenue splits are optional,
+// songs from before them were held outright by their owner
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacySong {
+    id: SongId,
     title: String,
     artist: String,
-    owner_id: u64,
+    owner_id: OwnerId,
     year: u32,
     genre: String,
     price: u32,
+    rights_holders: Option<Vec<RightsHolder>>,
+    approval_quorum_bps: Option<u64>,
+    work_id: Option<WorkId>,
+    isrc: Option<String>,
+    availability: Option<SongAvailability>,
+    duration_secs: Option<u32>,
+    bpm: Option<u32>,
+    musical_key: Option<MusicalKey>,
+    mood_tags: Option<Vec<String>>,
+    language: Option<String>,
+    explicit: Option<bool>,
+    version: Option<u64>,
+}
+
+impl From<LegacySong> for Song {
+    fn from(legacy: LegacySong) -> Self {
+        Song {
+            id: legacy.id,
+            title: legacy.title,
+            artist: legacy.artist,
+            owner_id: legacy.owner_id,
+            year: legacy.year,
+            genre: legacy.genre,
+            price: legacy.price,
+            rights_holders: legacy.rights_holders.unwrap_or_else(|| {
+                vec![RightsHolder {
+                    owner_id: legacy.owner_id,
+                    share_bps: BPS_DENOMINATOR,
+                    role: RightsRole::Owner,
+                }]
+            }),
+            approval_quorum_bps: legacy.approval_quorum_bps.unwrap_or(BPS_DENOMINATOR),
+            work_id: legacy.work_id,
+            isrc: legacy.isrc,
+            availability: legacy.availability.unwrap_or_else(SongAvailability::unrestricted),
+            duration_secs: legacy.duration_secs,
+            bpm: legacy.bpm,
+            musical_key: legacy.musical_key,
+            mood_tags: legacy.mood_tags.unwrap_or_default(),
+            language: legacy.language,
+            explicit: legacy.explicit.unwrap_or_default(),
+            version: legacy.version.unwrap_or(1),
+        }
+    }
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Owner {
-    id: u64,
+    id: OwnerId,
     name: String,
     email: String,
-    auth_key: String,
-    song_ids: Vec<u64>,
-    license_ids: Vec<u64>,
+    // Principal that created (or claimed) the owner record
+    principal: Option<Principal>,
+    // Legacy shared secret, only kept until the record is claimed
+    auth_key: Option<String>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct License {
-    id: u64,
-    song_id: u64,
-    owner_id: u64,
-    licensee_id: u64,
-    approved: bool,
+    id: LicenseId,
+    song_id: SongId,
+    // Managing owner of the song, or of its work for composition rights
+    owner_id: OwnerId,
+    licensee_id: LicenseeId,
+    // Right being granted, licenses from before rights were distinguished grant the master
+    right: LicenseRight,
+    // Scope of the deal, licenses from before scopes existed cover every use worldwide
+    usages: Vec<UsageType>,
+    media_channels: Vec<MediaChannel>,
+    territory: Territory,
+    exclusive: bool,
+    status: LicenseStatus,
+    // Agreed price and terms, set once an offer is accepted
     price: u32,
-    start_date: String,
-    end_date: String,
+    terms: String,
+    // Nanoseconds since the epoch
+    start_date: u64,
+    end_date: u64,
+    // Every status the license went through, oldest first
+    status_history: Vec<StatusChange>,
+    // Every offer made during negotiation, oldest first
+    offers: Vec<Offer>,
+    // Fees escrowed for the licensee's offers, oldest first, none for free licenses
+    payments: Vec<LicensePayment>,
+    // Rights holders who approved the latest offer
+    approvals: Vec<OwnerId>,
+    // How the owners' share of the approved fee is split between the rights holders
+    royalties: Vec<RoyaltyPayout>,
+    // Offer template the license was requested against
+    template_id: Option<TemplateId>,
+    // License this one renews, starting when it ends
+    renews: Option<LicenseId>,
+    // Whether the licensee may hand the license to another licensee
+    transfer_policy: TransferPolicy,
+    // Licensee the license is being handed to, waiting for the owner's consent
+    pending_transfer: Option<LicenseeId>,
+    // Every transfer between licensees, oldest first
+    transfers: Vec<LicenseTransfer>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Licensee {
-    id: u64,
-    name: String,
-    email: String,
-    licenses: Vec<u64>,
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug)]
+enum TransferPolicy {
+    #[default]
+    NotTransferable,
+    Transferable,
+    // Transfers wait until the license owner consents
+    OwnerConsent,
 }
 
-// Define return types for calls
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct ReturnOwner {
-    id: u64,
-    name: String,
-    email: String,
+struct LicenseTransfer {
+    from_licensee_id: LicenseeId,
+    to_licensee_id: LicenseeId,
+    timestamp: u64,
 }
 
-// Implement the 'Storable' trait for each of the data structures
-impl Storable for Song {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    // Conversion from bytes
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RoyaltyPayout {
+    owner_id: OwnerId,
+    amount: u64,
+    // Block of the transfer that paid the rights holder, pending until set
+    payout_block: Option<Nat>,
 }
 
-impl Storable for Owner {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LicensePayment {
+    ledger: Principal,
+    amount: u64,
+    // Block of the transfer_from that moved the fee into the license's escrow subaccount
+    collected_block: Nat,
+    // Part of the fee owed to the rights holders after the platform share
+    owner_share: u64,
+    // Block of the transfer that released the last royalty, pending until set
+    payout_block: Option<Nat>,
+    // Block of the transfer that returned the fee to the licensee
+    refund_block: Option<Nat>,
+    // Block of the transfer that moved the platform share to the canister's main account
+    platform_block: Option<Nat>,
 }
 
-impl Storable for License {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct IdSequences {
+    songs: u64,
+    works: u64,
+    owners: u64,
+    licenses: u64,
+    licensees: u64,
+    templates: u64,
 }
 
-impl Storable for Licensee {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl IdSequences {
+    fn starting_at(next_id: u64) -> Self {
+        IdSequences {
+            songs: next_id,
+            works: next_id,
+            owners: next_id,
+            licenses: next_id,
+            licensees: next_id,
+            templates: next_id,
+        }
     }
 }
 
-// Implement the 'BoundedStorable' trait for each of the data structures
-impl BoundedStorable for Song {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PaymentConfig {
+    // ICRC-1/ICRC-2 ledger license fees are paid on, payments are off until set
+    ledger: Option<Principal>,
+    // Share of every license fee the platform keeps, in basis points
+    platform_fee_bps: u64,
 }
 
-impl BoundedStorable for Owner {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Result of comparing one index map with the stored records
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IndexCheck {
+    index: String,
+    entries: u64,
+    // Entries the stored records call for that the index lacks
+    missing: u64,
+    // Entries in the index that no stored record calls for
+    stale: u64,
 }
 
-impl BoundedStorable for License {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// ICRC-1 and ICRC-2 ledger interface types
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
 }
 
-impl BoundedStorable for Licensee {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
 }
 
-// Define thread-local static variables for memory management and storage
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
 
-    static SONG_STORAGE: RefCell<StableBTreeMap<u64, Song, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
 
-    static OWNER_STORAGE: RefCell<StableBTreeMap<u64, Owner, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Debug)]
+enum LicenseStatus {
+    #[default]
+    Requested,
+    CounterOffered,
+    Approved,
+    Rejected,
+    Revoked,
+    Expired,
+    Cancelled,
+}
 
-    static LICENSE_STORAGE: RefCell<StableBTreeMap<u64, License, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StatusChange {
+    status: LicenseStatus,
+    timestamp: u64,
+}
 
-    static LICENSEE_STORAGE: RefCell<StableBTreeMap<u64, Licensee, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-    ));
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum OfferParty {
+    Owner,
+    Licensee,
 }
 
-// Define structs for payload data (used in update calls)
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
-struct SongPayload {
-    #[validate(length(min = 2))]
-    title: String,
-    artist: String,
-    owner_id: u64,
-    year: u32,
-    genre: String,
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Offer {
+    party: OfferParty,
     price: u32,
+    terms: String,
+    timestamp: u64,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
-struct OwnerPayload {
-    #[validate(length(min = 2))]
-    name: String,
-    email: String,
-    auth_key: String,
+// Ready-made offer an owner publishes for a song, licensees request licenses against it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OfferTemplate {
+    id: TemplateId,
+    song_id: SongId,
+    // Owner who published the template, the managing owner of the right it grants
+    owner_id: OwnerId,
+    right: LicenseRight,
+    usages: Vec<UsageType>,
+    media_channels: Vec<MediaChannel>,
+    territory: Territory,
+    exclusive: bool,
+    // License term in nanoseconds, counted from the requested start date
+    duration: u64,
+    price: u32,
+    terms: String,
+    // Whether requests count as approved by the publisher without calling approve_license
+    auto_approve: bool,
+    // Templates published before transfers existed grant non-transferable licenses
+    transfer_policy: Option<TransferPolicy>,
 }
 
+// License layout with nanosecond dates, fields added since then are optional
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct LicensePayload {
-    song_id: u64,
-    licensee_id: u64,
+struct StoredLicense {
+    id: LicenseId,
+    song_id: SongId,
+    owner_id: OwnerId,
+    licensee_id: LicenseeId,
+    right: Option<LicenseRight>,
+    usages: Option<Vec<UsageType>>,
+    media_channels: Option<Vec<MediaChannel>>,
+    territory: Option<Territory>,
+    exclusive: Option<bool>,
+    status: LicenseStatus,
+    price: u32,
+    terms: Option<String>,
+    start_date: u64,
+    end_date: u64,
+    status_history: Vec<StatusChange>,
+    offers: Option<Vec<Offer>>,
+    payment: Option<LicensePayment>,
+    payments: Option<Vec<LicensePayment>>,
+    approvals: Option<Vec<OwnerId>>,
+    royalties: Option<Vec<RoyaltyPayout>>,
+    transfer_policy: Option<TransferPolicy>,
+    transfers: Option<Vec<LicenseTransfer>>,
+}
+
+impl From<StoredLicense> for License {
+    fn from(stored: StoredLicense) -> Self {
+        License {
+            id: stored.id,
+            song_id: stored.song_id,
+            owner_id: stored.owner_id,
+            licensee_id: stored.licensee_id,
+            right: stored.right.unwrap_or_default(),
+            usages: stored.usages.unwrap_or_else(|| UsageType::ALL.to_vec()),
+            media_channels: stored.media_channels.unwrap_or_else(|| MediaChannel::ALL.to_vec()),
+            territory: stored.territory.unwrap_or_default(),
+            exclusive: stored.exclusive.unwrap_or_default(),
+            status: stored.status,
+            price: stored.price,
+            terms: stored.terms.unwrap_or_default(),
+            start_date: stored.start_date,
+            end_date: stored.end_date,
+            status_history: stored.status_history,
+            offers: stored.offers.unwrap_or_default(),
+            payments: stored
+                .payments
+                .unwrap_or_else(|| stored.payment.into_iter().collect()),
+            approvals: stored.approvals.unwrap_or_default(),
+            royalties: stored.royalties.unwrap_or_default(),
+            template_id: None,
+            renews: None,
+            transfer_policy: stored.transfer_policy.unwrap_or_default(),
+            pending_transfer: None,
+            transfers: stored.transfers.unwrap_or_default(),
+        }
+    }
+}
+
+// License layouts used while dates were free-form strings, with the
+// `approved` flag from before the status lifecycle or the status fields after it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacyLicense {
+    id: LicenseId,
+    song_id: SongId,
+    owner_id: OwnerId,
+    licensee_id: LicenseeId,
+    approved: Option<bool>,
+    status: Option<LicenseStatus>,
+    price: u32,
     start_date: String,
     end_date: String,
+    status_history: Option<Vec<StatusChange>>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
-struct LicenseePayload {
-    #[validate(length(min = 2))]
+impl From<LegacyLicense> for License {
+    fn from(legacy: LegacyLicense) -> Self {
+        // Revoked licenses without a status were stored as not approved, so they resume as requests
+        let status = legacy.status.unwrap_or(if legacy.approved.unwrap_or(false) {
+            LicenseStatus::Approved
+        } else {
+            LicenseStatus::Requested
+        });
+        License {
+            id: legacy.id,
+            song_id: legacy.song_id,
+            owner_id: legacy.owner_id,
+            licensee_id: legacy.licensee_id,
+            status,
+            price: legacy.price,
+            // Unreadable dates must not expire a license, so they become open-ended
+            start_date: parse_date(&legacy.start_date).unwrap_or(0),
+            end_date: parse_date(&legacy.end_date).unwrap_or(u64::MAX),
+            status_history: legacy.status_history.unwrap_or_default(),
+            usages: UsageType::ALL.to_vec(),
+            media_channels: MediaChannel::ALL.to_vec(),
+            ..Default::default()
+        }
+    }
+}
+
+impl LicenseStatus {
+    // Check whether a license may move from this status to the next one
+    fn can_transition_to(&self, next: LicenseStatus) -> bool {
+        use LicenseStatus::*;
+        matches!(
+            (self, next),
+            (Requested, CounterOffered)
+                | (Requested, Approved)
+                | (Requested, Rejected)
+                | (Requested, Cancelled)
+                | (Requested, Expired)
+                | (CounterOffered, Requested)
+                | (CounterOffered, Approved)
+                | (CounterOffered, Rejected)
+                | (CounterOffered, Cancelled)
+                | (Approved, Revoked)
+                | (Approved, Expired)
+        )
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Licensee {
+    id: LicenseeId,
     name: String,
     email: String,
+    // Principal that created the licensee record
+    principal: Option<Principal>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct ProtectedPayload {
-    auth_key: String,
-    license_id: u64,
+// Relationship lists kept on owner and licensee records by earlier versions,
+// read once on upgrade to move them into the index maps
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyOwnerLinks {
+    song_ids: Option<Vec<SongId>>,
+    license_ids: Option<Vec<LicenseId>>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyLicenseeLinks {
+    licenses: Option<Vec<LicenseId>>,
 }
 
+// Define return types for calls
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct ApprovePayload {
-    auth_key: String,
-    license_id: u64,
-    cost: u32,
+struct ReturnOwner {
+    id: OwnerId,
+    name: String,
+    email: String,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
-struct UpdateSongPayload {
-    auth_key: String,
-    id: u64,
-    #[validate(length(min = 2))]
-    title: String,
-    artist: String,
-    year: u32,
-    genre: String,
-    price: u32,
+// One page of a list query in id order, or the order a search or filter asked for,
+// the next page starts after next_cursor until it is None
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Page<T, K> {
+    items: Vec<T>,
+    next_cursor: Option<K>,
+    // Items matching the query across every page
+    total: u64,
 }
 
-// Define query functions to get all licensable songs
-#[ic_cdk::query]
-fn get_all_songs() -> Result<Vec<Song>, Error> {
-    // Retrieve all songs from the storage
-    let songs_vec: Vec<(u64, Song)> = SONG_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the songs from the tuple and create a vector
-    let songs: Vec<Song> = songs_vec.into_iter().map(|(_, song)| song).collect();
+// Conditions a song must meet to be found by find_songs, unset conditions match every song
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+struct SongFilter {
+    // Genres ignoring case, a song matches any of them, an empty list matches every genre
+    genres: Vec<String>,
+    year_from: Option<u32>,
+    year_to: Option<u32>,
+    min_price: Option<u32>,
+    max_price: Option<u32>,
+    owner_id: Option<OwnerId>,
+    // Artist ignoring case
+    artist: Option<String>,
+    // Use the song must be available for
+    usage: Option<UsageType>,
+    // Songs without a known tempo or length only match when these are unset
+    bpm_from: Option<u32>,
+    bpm_to: Option<u32>,
+    min_duration_secs: Option<u32>,
+    max_duration_secs: Option<u32>,
+    musical_key: Option<MusicalKey>,
+    // Moods and themes ignoring case, a song must carry all of them
+    mood_tags: Vec<String>,
+    // ISO 639-1 code ignoring case
+    language: Option<String>,
+    explicit: Option<bool>,
+    isrc: Option<String>,
+}
 
-    // Check if any songs are found
-    match songs.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no licensable songs could be found"),
-        }),
-        _ => Ok(songs),
+impl SongFilter {
+    fn matches(&self, song: &Song) -> bool {
+        (self.genres.is_empty()
+            || self
+                .genres
+                .iter()
+                .any(|genre| normalize_index_text(genre) == normalize_index_text(&song.genre)))
+            && self.year_from.is_none_or(|year| song.year >= year)
+            && self.year_to.is_none_or(|year| song.year <= year)
+            && self.min_price.is_none_or(|price| song.price >= price)
+            && self.max_price.is_none_or(|price| song.price <= price)
+            && self.owner_id.is_none_or(|owner_id| song.owner_id == owner_id)
+            && self
+                .artist
+                .as_ref()
+                .is_none_or(|artist| normalize_index_text(artist) == normalize_index_text(&song.artist))
+            && self.usage.is_none_or(|usage| song.availability.usages.contains(&usage))
+            && self.bpm_from.is_none_or(|bpm| song.bpm.is_some_and(|song_bpm| song_bpm >= bpm))
+            && self.bpm_to.is_none_or(|bpm| song.bpm.is_some_and(|song_bpm| song_bpm <= bpm))
+            && self
+                .min_duration_secs
+                .is_none_or(|secs| song.duration_secs.is_some_and(|duration| duration >= secs))
+            && self
+                .max_duration_secs
+                .is_none_or(|secs| song.duration_secs.is_some_and(|duration| duration <= secs))
+            && self.musical_key.is_none_or(|key| song.musical_key == Some(key))
+            && self
+                .mood_tags
+                .iter()
+                .all(|tag| song.mood_tags.contains(&normalize_index_text(tag)))
+            && self
+                .language
+                .as_ref()
+                .is_none_or(|language| song.language.as_deref() == Some(language.trim().to_ascii_lowercase().as_str()))
+            && self.explicit.is_none_or(|explicit| song.explicit == explicit)
+            && self
+                .isrc
+                .as_ref()
+                .is_none_or(|isrc| song.isrc.is_some() && song.isrc == normalize_isrc(isrc).ok())
     }
 }
 
-// Define query functions to get songs by id
-#[ic_cdk::query]
-fn get_song(id: u64) -> Result<Song, Error> {
-    // Try to get the song by id
-    match _get_song(&id) {
-        Some(song) => Ok(song),
-        None => Err(Error::NotFound {
-            msg: format!("song id:{} could not be found", id),
-        }),
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum SongSortKey {
+    Price,
+    Year,
+    Title,
 }
 
-// Helper function to get a song by id
-fn _get_song(id: &u64) -> Option<Song> {
-    SONG_STORAGE.with(|s| s.borrow().get(id))
+// Order of the songs found by find_songs, ties keep id order
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct SongSort {
+    key: SongSortKey,
+    descending: bool,
 }
 
-// Define update functions to create new songs
-#[ic_cdk::update]
-fn create_song(payload: SongPayload) -> Result<Song, Error> {
-    // Validate Payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+impl SongSort {
+    fn compare(&self, a: &Song, b: &Song) -> std::cmp::Ordering {
+        let ordering = match self.key {
+            SongSortKey::Price => a.price.cmp(&b.price),
+            SongSortKey::Year => a.year.cmp(&b.year),
+            SongSortKey::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+        };
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
     }
+}
 
-    if _get_owner(&payload.owner_id).is_none() {
-        return Err(Error::NotFound {
-            msg: format!("owner id:{} could not be found, add ownwer first", payload.owner_id.clone()),
-        });
-    }
+// Number of songs per genre, decade and price band
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CatalogFacets {
+    total: u64,
+    // Most common genres first
+    genres: Vec<GenreCount>,
+    decades: Vec<DecadeCount>,
+    price_bands: Vec<PriceBandCount>,
+}
 
-    // Increment the global ID counter to get a new unique ID
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GenreCount {
+    // Genre trimmed and lowercased, as songs are grouped ignoring case
+    genre: String,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DecadeCount {
+    // First year of the decade
+    decade: u32,
+    count: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PriceBandCount {
+    min_price: u32,
+    // None for the highest band
+    max_price: Option<u32>,
+    count: u64,
+}
+
+// A song matching a search query, ranked from 1 by score
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchHit {
+    rank: u32,
+    score: f64,
+    song: Song,
+}
+
+// Helper function to decode a stored record, falling back to the layout stored while ids were plain nat64 values
+fn decode_stored<T>(bytes: &[u8]) -> candid::Result<T>
+where
+    T: candid::CandidType + serde::de::DeserializeOwned,
+{
+    Decode!(bytes, T).or_else(|_| reencode_stored(bytes))
+}
+
+// Helper function to decode a record stored with plain nat64 ids by wrapping each id into its record
+// and encoding the value again under the current type. Optional ids of the old layout decode directly
+// as None, so records that only hold optional ids are always read through here
+fn reencode_stored<T>(bytes: &[u8]) -> candid::Result<T>
+where
+    T: candid::CandidType + serde::de::DeserializeOwned,
+{
+    let ty = T::ty();
+    let value = candid::IDLArgs::from_bytes(bytes)?
+        .args
+        .into_iter()
+        .next()
+        .ok_or_else(|| candid::Error::msg("no stored value"))?;
+    let value = wrap_plain_ids(value, &ty).annotate_type(false, &candid::TypeEnv::new(), &ty)?;
+    Decode!(&Encode!(&Reencoded::<T>(value, std::marker::PhantomData))?, T)
+}
+
+// Helper function to wrap the nat64 values found where the type expects an id record
+fn wrap_plain_ids(value: candid::IDLValue, ty: &candid::types::Type) -> candid::IDLValue {
+    use candid::types::value::{IDLField, VariantValue};
+    use candid::types::TypeInner;
+    use candid::IDLValue;
+
+    match (value, ty.as_ref()) {
+        (IDLValue::Nat64(id), TypeInner::Record(fields))
+            if fields.len() == 1 && *fields[0].ty.as_ref() == TypeInner::Nat64 =>
+        {
+            IDLValue::Record(vec![IDLField {
+                id: fields[0].id.as_ref().clone(),
+                val: IDLValue::Nat64(id),
+            }])
+        }
+        (IDLValue::Opt(value), TypeInner::Opt(ty)) => IDLValue::Opt(Box::new(wrap_plain_ids(*value, ty))),
+        (IDLValue::Vec(values), TypeInner::Vec(ty)) => {
+            IDLValue::Vec(values.into_iter().map(|value| wrap_plain_ids(value, ty)).collect())
+        }
+        (IDLValue::Record(values), TypeInner::Record(fields)) => IDLValue::Record(
+            values
+                .into_iter()
+                .map(|field| match fields.iter().find(|f| *f.id == field.id) {
+                    Some(f) => IDLField {
+                        val: wrap_plain_ids(field.val, &f.ty),
+                        id: field.id,
+                    },
+                    None => field,
+                })
+                .collect(),
+        ),
+        (IDLValue::Variant(VariantValue(field, index)), TypeInner::Variant(fields)) => {
+            let field = match fields.iter().find(|f| *f.id == field.id) {
+                Some(f) => IDLField {
+                    val: wrap_plain_ids(field.val, &f.ty),
+                    id: field.id,
+                },
+                None => *field,
+            };
+            IDLValue::Variant(VariantValue(Box::new(field), index))
+        }
+        (value, _) => value,
+    }
+}
+
+// A value annotated with the type of T, encoded under that type
+struct Reencoded<T>(candid::IDLValue, std::marker::PhantomData<T>);
+
+impl<T: candid::CandidType> candid::CandidType for Reencoded<T> {
+    fn _ty() -> candid::types::Type {
+        T::ty()
+    }
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+// Implement the 'Storable' trait for each of the data structures
+impl Storable for Song {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes, falling back to the layouts stored by earlier versions
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored::<Self>(&bytes).unwrap_or_else(|_| decode_stored::<LegacySong>(&bytes).unwrap().into())
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Work {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(&bytes).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_RECORD_BYTES,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Owner {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(&bytes).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_RECORD_BYTES,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for License {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Fall back to older layouts for licenses stored by earlier versions
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored::<Self>(&bytes)
+            .or_else(|_| decode_stored::<StoredLicense>(&bytes).map(License::from))
+            .unwrap_or_else(|_| decode_stored::<LegacyLicense>(&bytes).unwrap().into())
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for OfferTemplate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(&bytes).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PaymentConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for IdSequences {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Licensee {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored(&bytes).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_RECORD_BYTES,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for IndexText {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        IndexText(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_INDEX_TEXT_BYTES as u32,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for LegacyOwnerLinks {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        reencode_stored(&bytes).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_RECORD_BYTES,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for LegacyLicenseeLinks {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        reencode_stored(&bytes).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_RECORD_BYTES,
+        is_fixed_size: false,
+    };
+}
+
+// Define thread-local static variables for memory management and storage
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    // Global id counter used before every entity had its own sequence, kept to seed them
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // Next id of each entity, every sequence starts past the ids the global counter handed out
+    static ID_SEQUENCES: RefCell<Cell<IdSequences, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+            IdSequences::starting_at(ID_COUNTER.with(|counter| *counter.borrow().get())),
+        )
+        .expect("Cannot create the id sequences")
+    );
+
+    static SONG_STORAGE: RefCell<StableBTreeMap<SongId, Song, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static OWNER_STORAGE: RefCell<StableBTreeMap<OwnerId, Owner, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static LICENSE_STORAGE: RefCell<StableBTreeMap<LicenseId, License, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static LICENSEE_STORAGE: RefCell<StableBTreeMap<LicenseeId, Licensee, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static MAX_LICENSE_DURATION: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), DEFAULT_MAX_LICENSE_DURATION)
+            .expect("Cannot create the max license duration")
+    );
+
+    static PAYMENT_CONFIG: RefCell<Cell<PaymentConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), PaymentConfig::default())
+            .expect("Cannot create the payment config")
+    );
+
+    static WORK_STORAGE: RefCell<StableBTreeMap<WorkId, Work, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static TEMPLATE_STORAGE: RefCell<StableBTreeMap<TemplateId, OfferTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Relationship indexes, each entry links a record to one of its songs or licenses
+    static OWNER_SONG_INDEX: RefCell<IndexMap<(OwnerId, SongId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static OWNER_LICENSE_INDEX: RefCell<IndexMap<(OwnerId, LicenseId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static LICENSEE_LICENSE_INDEX: RefCell<IndexMap<(LicenseeId, LicenseId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Secondary indexes, kept in step with the records whenever they are written through StagedWrites
+    static GENRE_SONG_INDEX: RefCell<IndexMap<(IndexText, SongId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static ARTIST_SONG_INDEX: RefCell<IndexMap<(IndexText, SongId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static YEAR_SONG_INDEX: RefCell<IndexMap<(u32, SongId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Songs and works each owner holds a share of the rights in
+    static HOLDER_SONG_INDEX: RefCell<IndexMap<(OwnerId, SongId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static HOLDER_WORK_INDEX: RefCell<IndexMap<(OwnerId, WorkId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static WORK_SONG_INDEX: RefCell<IndexMap<(WorkId, SongId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // Every license by song, by licensee and by managing owner, whatever its status
+    static SONG_LICENSE_INDEX: RefCell<IndexMap<(SongId, LicenseId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    static LICENSEE_REQUEST_INDEX: RefCell<IndexMap<(LicenseeId, LicenseId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    static OWNER_REQUEST_INDEX: RefCell<IndexMap<(OwnerId, LicenseId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // Offer templates by the song they are published for
+    static SONG_TEMPLATE_INDEX: RefCell<IndexMap<(SongId, TemplateId)>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Inverted index of the terms in each song's title, artist, genre and year, valued by the term's weight
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(IndexText, SongId), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // Layout the search index was built with, 0 for an index built before layouts were recorded
+    static SEARCH_LAYOUT: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))), 0)
+            .expect("Cannot create the search layout")
+    );
+
+    // Number of songs per genre, decade and price band, kept up to date as songs change
+    static GENRE_COUNTS: RefCell<StableBTreeMap<IndexText, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static DECADE_COUNTS: RefCell<StableBTreeMap<u32, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static PRICE_BAND_COUNTS: RefCell<StableBTreeMap<u32, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // Licenses with a ledger call in flight, they cannot change status until it returns
+    static PAYMENTS_IN_PROGRESS: RefCell<BTreeSet<LicenseId>> = RefCell::default();
+}
+
+// Arm the license expiry timer when the canister is installed
+#[ic_cdk::init]
+fn init() {
+    record_search_layout();
+    start_expiry_sweeper();
+}
+
+// Timers do not survive upgrades, so arm the expiry timer again
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_relationship_lists();
+    build_secondary_indexes();
+    start_expiry_sweeper();
+}
+
+// Helper function to build the secondary indexes added after records were already stored
+fn build_secondary_indexes() {
+    backfill_index(&GENRE_SONG_INDEX, || expected_entries(&SONG_STORAGE, genre_song_entries));
+    backfill_index(&ARTIST_SONG_INDEX, || expected_entries(&SONG_STORAGE, artist_song_entries));
+    backfill_index(&YEAR_SONG_INDEX, || expected_entries(&SONG_STORAGE, year_song_entries));
+    backfill_index(&HOLDER_SONG_INDEX, || expected_entries(&SONG_STORAGE, holder_song_entries));
+    backfill_index(&WORK_SONG_INDEX, || expected_entries(&SONG_STORAGE, work_song_entries));
+    backfill_index(&HOLDER_WORK_INDEX, || expected_entries(&WORK_STORAGE, holder_work_entries));
+    backfill_index(&SONG_LICENSE_INDEX, || expected_entries(&LICENSE_STORAGE, song_license_entries));
+    backfill_index(&LICENSEE_REQUEST_INDEX, || expected_entries(&LICENSE_STORAGE, licensee_request_entries));
+    backfill_index(&OWNER_REQUEST_INDEX, || expected_entries(&LICENSE_STORAGE, owner_request_entries));
+    backfill_index(&OWNER_LICENSE_INDEX, || expected_entries(&LICENSE_STORAGE, owner_license_entries));
+    backfill_index(&LICENSEE_LICENSE_INDEX, || expected_entries(&LICENSE_STORAGE, licensee_license_entries));
+    backfill_index(&SONG_TEMPLATE_INDEX, || expected_entries(&TEMPLATE_STORAGE, song_template_entries));
+    rebuild_search_index();
+    backfill_index(&GENRE_COUNTS, || expected_counts(genre_facet));
+    backfill_index(&DECADE_COUNTS, || expected_counts(decade_facet));
+    backfill_index(&PRICE_BAND_COUNTS, || expected_counts(price_band_facet));
+}
+
+// Helper function to rebuild the search index from the stored songs when it was built with an earlier layout
+fn rebuild_search_index() {
+    if SEARCH_LAYOUT.with(|layout| *layout.borrow().get()) == SEARCH_INDEX_LAYOUT {
+        return;
+    }
+    SEARCH_INDEX.with(|index| index.borrow_mut().clear_new());
+    backfill_index(&SEARCH_INDEX, expected_search_entries);
+    record_search_layout();
+}
+
+// Helper function to record that the search index holds the terms of the current layout
+fn record_search_layout() {
+    SEARCH_LAYOUT
+        .with(|layout| layout.borrow_mut().set(SEARCH_INDEX_LAYOUT))
+        .expect("Cannot set the search layout");
+}
+
+// Helper function to move the relationship lists of records stored by earlier versions into the index maps
+fn migrate_relationship_lists() {
+    // Read the lists through a second view of the storage memory, the view never writes
+    let owner_links: Vec<(OwnerId, LegacyOwnerLinks)> = {
+        let legacy: StableBTreeMap<OwnerId, LegacyOwnerLinks, Memory> =
+            StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))));
+        legacy
+            .iter()
+            .filter(|(_, links)| links.song_ids.is_some() || links.license_ids.is_some())
+            .collect()
+    };
+    let licensee_links: Vec<(LicenseeId, LegacyLicenseeLinks)> = {
+        let legacy: StableBTreeMap<LicenseeId, LegacyLicenseeLinks, Memory> =
+            StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))));
+        legacy.iter().filter(|(_, links)| links.licenses.is_some()).collect()
+    };
+
+    for (owner_id, links) in owner_links {
+        OWNER_SONG_INDEX.with(|index| {
+            for song_id in links.song_ids.unwrap_or_default() {
+                index.borrow_mut().insert((owner_id, song_id), ());
+            }
+        });
+        OWNER_LICENSE_INDEX.with(|index| {
+            for license_id in links.license_ids.unwrap_or_default() {
+                index.borrow_mut().insert((owner_id, license_id), ());
+            }
+        });
+        // Writing the record back drops the lists from its stored layout
+        OWNER_STORAGE.with(|s| {
+            let mut storage = s.borrow_mut();
+            if let Some(owner) = storage.get(&owner_id) {
+                storage.insert(owner_id, owner);
+            }
+        });
+    }
+
+    for (licensee_id, links) in licensee_links {
+        LICENSEE_LICENSE_INDEX.with(|index| {
+            for license_id in links.licenses.unwrap_or_default() {
+                index.borrow_mut().insert((licensee_id, license_id), ());
+            }
+        });
+        LICENSEE_STORAGE.with(|s| {
+            let mut storage = s.borrow_mut();
+            if let Some(licensee) = storage.get(&licensee_id) {
+                storage.insert(licensee_id, licensee);
+            }
+        });
+    }
+}
+
+// Define structs for payload data (used in update calls)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct SongPayload {
+    #[validate(length(min = 2))]
+    title: String,
+    artist: String,
+    owner_id: OwnerId,
+    year: u32,
+    genre: String,
+    price: u32,
+    // Must include the owner adding the song, shares add up to 10_000 basis points
+    rights_holders: Vec<RightsHolder>,
+    approval_quorum_bps: u64,
+    work_id: Option<WorkId>,
+    isrc: Option<String>,
+    availability: SongAvailability,
+    duration_secs: Option<u32>,
+    bpm: Option<u32>,
+    musical_key: Option<MusicalKey>,
+    mood_tags: Vec<String>,
+    language: Option<String>,
+    explicit: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct WorkPayload {
+    #[validate(length(min = 2, max = "MAX_BOUNDED_TEXT_CHARS"))]
+    title: String,
+    iswc: String,
+    owner_id: OwnerId,
+    // Must include the owner adding the work, shares add up to 10_000 basis points
+    rights_holders: Vec<RightsHolder>,
+    approval_quorum_bps: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct UpdateWorkPayload {
+    id: WorkId,
+    #[validate(length(min = 2, max = "MAX_BOUNDED_TEXT_CHARS"))]
+    title: String,
+    iswc: String,
+    rights_holders: Vec<RightsHolder>,
+    approval_quorum_bps: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct OwnerPayload {
+    #[validate(length(min = 2, max = "MAX_BOUNDED_TEXT_CHARS"))]
+    name: String,
+    #[validate(length(max = "MAX_BOUNDED_TEXT_CHARS"))]
+    email: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct LicensePayload {
+    song_id: SongId,
+    licensee_id: LicenseeId,
+    right: LicenseRight,
+    // Scope of the deal, checked against the song's availability
+    usages: Vec<UsageType>,
+    media_channels: Vec<MediaChannel>,
+    territory: Territory,
+    exclusive: bool,
+    // Nanoseconds since the epoch
+    start_date: u64,
+    end_date: u64,
+    // Price and terms the licensee proposes
+    price: u32,
+    #[validate(length(max = 100))]
+    terms: String,
+    transfer_policy: TransferPolicy,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct OfferTemplatePayload {
+    song_id: SongId,
+    right: LicenseRight,
+    usages: Vec<UsageType>,
+    media_channels: Vec<MediaChannel>,
+    territory: Territory,
+    exclusive: bool,
+    // License term in nanoseconds
+    duration: u64,
+    price: u32,
+    #[validate(length(max = 100))]
+    terms: String,
+    auto_approve: bool,
+    transfer_policy: TransferPolicy,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TemplateLicensePayload {
+    template_id: TemplateId,
+    licensee_id: LicenseeId,
+    // Nanoseconds since the epoch, the template's duration sets the end date
+    start_date: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RenewalPayload {
+    license_id: LicenseId,
+    // Nanoseconds since the epoch, the renewal starts when the renewed license ends
+    end_date: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferPayload {
+    license_id: LicenseId,
+    // Licensee the license is handed to
+    licensee_id: LicenseeId,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct LicenseePayload {
+    #[validate(length(min = 2, max = "MAX_BOUNDED_TEXT_CHARS"))]
+    name: String,
+    #[validate(length(max = "MAX_BOUNDED_TEXT_CHARS"))]
+    email: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProtectedPayload {
+    license_id: LicenseId,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApprovePayload {
+    license_id: LicenseId,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct OfferPayload {
+    license_id: LicenseId,
+    price: u32,
+    #[validate(length(max = 100))]
+    terms: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClaimOwnerPayload {
+    owner_id: OwnerId,
+    auth_key: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BindLicenseePayload {
+    licensee_id: LicenseeId,
+    principal: Principal,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct UpdateSongPayload {
+    id: SongId,
+    // Version of the song the update was made against, the update is refused once the song moved on
+    version: u64,
+    #[validate(length(min = 2))]
+    title: String,
+    artist: String,
+    year: u32,
+    genre: String,
+    price: u32,
+    rights_holders: Vec<RightsHolder>,
+    approval_quorum_bps: u64,
+    work_id: Option<WorkId>,
+    isrc: Option<String>,
+    availability: SongAvailability,
+    duration_secs: Option<u32>,
+    bpm: Option<u32>,
+    musical_key: Option<MusicalKey>,
+    mood_tags: Vec<String>,
+    language: Option<String>,
+    explicit: bool,
+}
+
+// Changes to a song, unset fields keep their current value and Some(None) clears an optional field
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+struct PatchSongPayload {
+    id: SongId,
+    // Version of the song the changes were made against
+    version: u64,
+    #[validate(length(min = 2))]
+    title: Option<String>,
+    artist: Option<String>,
+    year: Option<u32>,
+    genre: Option<String>,
+    price: Option<u32>,
+    rights_holders: Option<Vec<RightsHolder>>,
+    approval_quorum_bps: Option<u64>,
+    work_id: Option<Option<WorkId>>,
+    isrc: Option<Option<String>>,
+    availability: Option<SongAvailability>,
+    duration_secs: Option<Option<u32>>,
+    bpm: Option<Option<u32>>,
+    musical_key: Option<Option<MusicalKey>>,
+    mood_tags: Option<Vec<String>>,
+    language: Option<Option<String>>,
+    explicit: Option<bool>,
+}
+
+// Define query functions to get all licensable songs, one page at a time
+#[ic_cdk::query]
+fn get_all_songs(limit: Option<u32>, start_after: Option<SongId>) -> Result<Page<Song, SongId>, Error> {
+    let limit = page_limit(limit)?;
+
+    // Read one song past the page from storage to know whether another page follows
+    let after = start_after.map_or(std::ops::Bound::Unbounded, std::ops::Bound::Excluded);
+    let (mut songs, total): (Vec<Song>, u64) = SONG_STORAGE.with(|s| {
+        let storage = s.borrow();
+        let songs = storage
+            .range((after, std::ops::Bound::Unbounded))
+            .take(limit + 1)
+            .map(|(_, song)| song)
+            .collect();
+        (songs, storage.len())
+    });
+
+    // Check if any songs are found
+    if total == 0 {
+        return Err(Error::NotFound {
+            msg: "no licensable songs could be found".to_string(),
+        });
+    }
+
+    let next_cursor = match songs.len() > limit {
+        true => {
+            songs.truncate(limit);
+            songs.last().map(|song| song.id)
+        }
+        false => None,
+    };
+    Ok(Page {
+        items: songs,
+        next_cursor,
+        total,
+    })
+}
+
+// Helper function to check the page size asked for, falling back to the default
+fn page_limit(limit: Option<u32>) -> Result<usize, Error> {
+    match limit.unwrap_or(DEFAULT_PAGE_LIMIT) {
+        limit @ 1..=MAX_PAGE_LIMIT => Ok(limit as usize),
+        limit => Err(Error::InvalidPayload {
+            msg: format!("limit:{} must be between 1 and {}", limit, MAX_PAGE_LIMIT),
+        }),
+    }
+}
+
+// Helper function to page through ids listed in id order, loading only the records on the page
+fn page_of_ids<K: Ord + Copy, T>(
+    ids: Vec<K>,
+    start_after: Option<K>,
+    limit: usize,
+    load: impl FnMut(&K) -> Option<T>,
+) -> Page<T, K> {
+    let total = ids.len() as u64;
+    let mut page_ids: Vec<K> = ids
+        .into_iter()
+        .filter(|id| start_after.is_none_or(|cursor| *id > cursor))
+        .take(limit + 1)
+        .collect();
+
+    let next_cursor = match page_ids.len() > limit {
+        true => {
+            page_ids.truncate(limit);
+            page_ids.last().copied()
+        }
+        false => None,
+    };
+    Page {
+        items: page_ids.iter().filter_map(load).collect(),
+        next_cursor,
+        total,
+    }
+}
+
+// Helper function to page through ids listed in id order whose records pass a check,
+// every record is checked so the total counts the matches, but only the page's records are kept
+fn page_of_matching_ids<K: Ord + Copy, T>(
+    ids: Vec<K>,
+    start_after: Option<K>,
+    limit: usize,
+    mut load: impl FnMut(&K) -> Option<T>,
+) -> Page<T, K> {
+    let mut total = 0;
+    let mut items = Vec::new();
+    let mut last_id = None;
+    let mut next_cursor = None;
+    for id in ids {
+        let item = match load(&id) {
+            Some(item) => item,
+            None => continue,
+        };
+        total += 1;
+        if start_after.is_some_and(|cursor| id <= cursor) || next_cursor.is_some() {
+            continue;
+        }
+        if items.len() == limit {
+            next_cursor = last_id;
+            continue;
+        }
+        items.push(item);
+        last_id = Some(id);
+    }
+
+    Page {
+        items,
+        next_cursor,
+        total,
+    }
+}
+
+// Define query functions to get songs by id
+#[ic_cdk::query]
+fn get_song(id: SongId) -> Result<Song, Error> {
+    // Try to get the song by id
+    match _get_song(&id) {
+        Some(song) => Ok(song),
+        None => Err(Error::NotFound {
+            msg: format!("song id:{} could not be found", id),
+        }),
+    }
+}
+
+// Helper function to get a song by id
+fn _get_song(id: &SongId) -> Option<Song> {
+    SONG_STORAGE.with(|s| s.borrow().get(id))
+}
+
+// Define update functions to create new songs
+#[ic_cdk::update]
+fn create_song(payload: SongPayload) -> Result<Song, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    let owner = match _get_owner(&payload.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found, add ownwer first", payload.owner_id),
+            })
+        }
+    };
+
+    // Only the owner can add songs to their catalog
+    authorize_owner(&owner, "add songs")?;
+
+    // Check the revenue split and the recording codes before the song is created
+    validate_rights_holders(payload.owner_id, &payload.rights_holders, payload.approval_quorum_bps)?;
+    validate_song_work(payload.work_id)?;
+    let isrc = match payload.isrc {
+        Some(isrc) => Some(normalize_isrc(&isrc)?),
+        None => None,
+    };
+    let availability = normalize_availability(payload.availability)?;
+    validate_song_timing(payload.duration_secs, payload.bpm)?;
+    let mood_tags = normalize_mood_tags(payload.mood_tags)?;
+    let language = normalize_language(payload.language)?;
+
+    // Allocate the next song id now that the request is valid
+    let id = SongId(next_id(|sequences| &mut sequences.songs));
+
+    // Create a new song based on the provided payload
+    let song = Song {
+        id,
+        title: payload.title.clone(),
+        artist: payload.artist,
+        owner_id: payload.owner_id,
+        year: payload.year,
+        genre: payload.genre,
+        price: payload.price,
+        rights_holders: payload.rights_holders,
+        approval_quorum_bps: payload.approval_quorum_bps,
+        work_id: payload.work_id,
+        isrc,
+        availability,
+        duration_secs: payload.duration_secs,
+        bpm: payload.bpm,
+        musical_key: payload.musical_key,
+        mood_tags,
+        language,
+        explicit: payload.explicit,
+        version: 1,
+    };
+
+    // Refuse to overwrite an existing song
+    if _get_song(&id).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("song title:{} could not be created", payload.title),
+        });
+    }
+
+    // Add the new song to the owner's list of songs
+    let mut staged = StagedWrites::default();
+    match add_song_to_owner(&mut staged, song.owner_id, song.id) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    // Store the new song together with the owner's list
+    staged.insert_song(song.clone());
+    staged.commit();
+    Ok(song)
+}
+
+// Define query functions to get owners by id
+#[ic_cdk::query]
+fn get_song_owner(id: SongId) -> Result<ReturnOwner, Error> {
+    // Retrieve the song by id
+    let song = match _get_song(&id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the song
+    match _get_owner(&song.owner_id) {
+        Some(owner) => Ok(ReturnOwner {
+            id: owner.id,
+            name: owner.name,
+            email: owner.email,
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("owner id:{} could not be found", song.owner_id),
+        }),
+    }
+}
+
+// Define query function to get the songs of an owner, one page at a time
+#[ic_cdk::query]
+fn get_owner_songs(
+    id: OwnerId,
+    limit: Option<u32>,
+    start_after: Option<SongId>,
+) -> Result<Page<Song, SongId>, Error> {
+    let limit = page_limit(limit)?;
+    if _get_owner(&id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("owner id:{} could not be found", id),
+        });
+    }
+
+    // Read the owner's song ids from its range of the index
+    Ok(page_of_ids(index_ids(&OWNER_SONG_INDEX, id), start_after, limit, _get_song))
+}
+
+// Define query function to get the songs of a genre ignoring case, one page at a time
+#[ic_cdk::query]
+fn get_songs_by_genre(
+    genre: String,
+    limit: Option<u32>,
+    start_after: Option<SongId>,
+) -> Result<Page<Song, SongId>, Error> {
+    let limit = page_limit(limit)?;
+    let page = page_of_text_matches(&GENRE_SONG_INDEX, &genre, |song| &song.genre, start_after, limit);
+
+    match page.total {
+        0 => Err(Error::NotFound {
+            msg: format!("no songs could be found with genre:{}", genre),
+        }),
+        _ => Ok(page),
+    }
+}
+
+// Define query function to get the songs of an artist ignoring case, one page at a time
+#[ic_cdk::query]
+fn get_songs_by_artist(
+    artist: String,
+    limit: Option<u32>,
+    start_after: Option<SongId>,
+) -> Result<Page<Song, SongId>, Error> {
+    let limit = page_limit(limit)?;
+    let page = page_of_text_matches(&ARTIST_SONG_INDEX, &artist, |song| &song.artist, start_after, limit);
+
+    match page.total {
+        0 => Err(Error::NotFound {
+            msg: format!("no songs could be found with artist:{}", artist),
+        }),
+        _ => Ok(page),
+    }
+}
+
+// Helper function to page through the songs whose genre or artist is a text ignoring case,
+// only a query that may have been cut in the index needs each song's full text compared
+fn page_of_text_matches(
+    index: &'static LocalKey<RefCell<IndexMap<(IndexText, SongId)>>>,
+    text: &str,
+    field: impl Fn(&Song) -> &String,
+    start_after: Option<SongId>,
+    limit: usize,
+) -> Page<Song, SongId> {
+    let key = IndexText::new(text);
+    let cut = key.may_be_cut();
+    let song_ids = index_ids(index, key);
+    match cut {
+        true => {
+            let query = normalize_index_text(text);
+            page_of_matching_ids(song_ids, start_after, limit, |song_id| {
+                _get_song(song_id).filter(|song| normalize_index_text(field(song)) == query)
+            })
+        }
+        false => page_of_ids(song_ids, start_after, limit, _get_song),
+    }
+}
+
+// Define query function to get the songs released in a year, one page at a time
+#[ic_cdk::query]
+fn get_songs_by_year(year: u32, limit: Option<u32>, start_after: Option<SongId>) -> Result<Page<Song, SongId>, Error> {
+    let limit = page_limit(limit)?;
+    let song_ids = index_ids(&YEAR_SONG_INDEX, year);
+
+    match song_ids.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no songs could be found with year:{}", year),
+        }),
+        _ => Ok(page_of_ids(song_ids, start_after, limit, _get_song)),
+    }
+}
+
+// Helper function to normalize the genre or artist a song is indexed and looked up by
+fn normalize_index_text(text: &str) -> String {
+    text.trim().to_lowercase()
+}
+
+// Helper function to split text into search terms, folding case and diacritics so "Beyoncé" matches "beyonce"
+fn search_terms(text: &str) -> Vec<String> {
+    let mut folded = String::new();
+    for c in text.nfkd().filter(|c| !is_combining_mark(*c)).flat_map(char::to_lowercase) {
+        match c {
+            // Apostrophes join their word so "don't" matches "dont"
+            '\'' | '\u{2019}' => {}
+            'ß' => folded.push_str("ss"),
+            'æ' => folded.push_str("ae"),
+            'œ' => folded.push_str("oe"),
+            'þ' => folded.push_str("th"),
+            'ø' => folded.push('o'),
+            'ł' => folded.push('l'),
+            'đ' | 'ð' => folded.push('d'),
+            c => folded.push(c),
+        }
+    }
+    folded
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Helper function to parse a search query into groups of terms, a song matches a group holding all of its terms
+// and the query when it matches any group, "OR" starts a new group and "AND" may be written between terms
+fn parse_search_query(query: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut groups: Vec<Vec<String>> = vec![Vec::new()];
+    for word in query.split_whitespace() {
+        match word {
+            "OR" => groups.push(Vec::new()),
+            "AND" => {}
+            word => groups.last_mut().unwrap().extend(search_terms(word)),
+        }
+    }
+    groups.retain(|group| !group.is_empty());
+
+    let terms: usize = groups.iter().map(Vec::len).sum();
+    if terms == 0 {
+        return Err(Error::InvalidPayload {
+            msg: format!("search query:{} holds no searchable terms", query),
+        });
+    }
+    if terms > MAX_SEARCH_TERMS {
+        return Err(Error::InvalidPayload {
+            msg: format!("search query holds {} terms, at most {} are allowed", terms, MAX_SEARCH_TERMS),
+        });
+    }
+    Ok(groups)
+}
+
+// Helper function to score the songs matching one search term, each song keeps its best scoring indexed term
+fn score_search_term(term: &str, song_count: u64) -> BTreeMap<SongId, f64> {
+    let term = IndexText::new(term);
+    let prefix = term.0.chars().count() >= MIN_PREFIX_CHARS;
+    let mut postings: BTreeMap<IndexText, Vec<(SongId, u32)>> = BTreeMap::new();
+    SEARCH_INDEX.with(|index| {
+        for ((indexed, song_id), weight) in index
+            .borrow()
+            .range((term.clone(), SongId::default())..)
+            .take_while(|((indexed, _), _)| *indexed == term || (prefix && indexed.0.starts_with(&term.0)))
+        {
+            postings.entry(indexed).or_default().push((song_id, weight));
+        }
+    });
+
+    let mut scores: BTreeMap<SongId, f64> = BTreeMap::new();
+    for (indexed, songs) in postings {
+        // Terms found in fewer songs tell more about the songs they are found in
+        let idf = (1.0 + song_count as f64 / songs.len() as f64).ln();
+        let factor = if indexed == term { 1.0 } else { PREFIX_MATCH_FACTOR };
+        for (song_id, weight) in songs {
+            let score = idf * weight as f64 * factor;
+            let best = scores.entry(song_id).or_insert(0.0);
+            *best = best.max(score);
+        }
+    }
+    scores
+}
+
+// Define query functions to search songs by the terms in their title, artist, genre and year,
+// returning a page of hits ranked by relevance, start_after is the rank of the last hit already seen
+#[ic_cdk::query]
+fn search_songs(query: String, limit: Option<u32>, start_after: Option<u32>) -> Result<Page<SearchHit, u32>, Error> {
+    let limit = page_limit(limit)?;
+    let groups = parse_search_query(&query)?;
+    let song_count = SONG_STORAGE.with(|s| s.borrow().len());
+
+    // Sum the scores of the terms within each group, dropping songs missing any of them,
+    // then sum the scores of every group a song matches
+    let mut scores: BTreeMap<SongId, f64> = BTreeMap::new();
+    for group in groups {
+        let mut group_scores: Option<BTreeMap<SongId, f64>> = None;
+        for term in group {
+            let term_scores = score_search_term(&term, song_count);
+            group_scores = Some(match group_scores {
+                None => term_scores,
+                Some(group_scores) => group_scores
+                    .into_iter()
+                    .filter_map(|(song_id, score)| term_scores.get(&song_id).map(|term_score| (song_id, score + term_score)))
+                    .collect(),
+            });
+        }
+        for (song_id, score) in group_scores.unwrap_or_default() {
+            *scores.entry(song_id).or_insert(0.0) += score;
+        }
+    }
+
+    // Handle cases where no songs are found
+    if scores.is_empty() {
+        return Err(Error::NotFound {
+            msg: format!("no songs could be found matching:{}", query),
+        });
+    }
+
+    // Rank the songs by score, ties keep id order
+    let mut ranked: Vec<(SongId, f64)> = scores.into_iter().collect();
+    ranked.sort_by(|(a_id, a_score), (b_id, b_score)| b_score.total_cmp(a_score).then(a_id.cmp(b_id)));
+    let ranks = (1..=ranked.len() as u32).collect();
+    Ok(page_of_ids(ranks, start_after, limit, |rank| {
+        let (song_id, score) = ranked[*rank as usize - 1];
+        _get_song(&song_id).map(|song| SearchHit {
+            rank: *rank,
+            score,
+            song,
+        })
+    }))
+}
+
+// Define query function to find the songs meeting a filter, sorted by price, year or title,
+// start_after is the position of the last song already seen
+#[ic_cdk::query]
+fn find_songs(
+    filter: SongFilter,
+    sort: Option<SongSort>,
+    limit: Option<u32>,
+    start_after: Option<u32>,
+) -> Result<Page<Song, u32>, Error> {
+    let limit = page_limit(limit)?;
+    validate_song_filter(&filter)?;
+
+    let mut songs: Vec<Song> = filter_candidates(&filter)
+        .into_iter()
+        .filter(|song| filter.matches(song))
+        .collect();
+
+    // Handle cases where no songs are found
+    if songs.is_empty() {
+        return Err(Error::NotFound {
+            msg: "no songs could be found matching the filter".to_string(),
+        });
+    }
+
+    // Songs are in id order and the sort is stable, so ties keep id order
+    if let Some(sort) = sort {
+        songs.sort_by(|a, b| sort.compare(a, b));
+    }
+    let positions = (1..=songs.len() as u32).collect();
+    Ok(page_of_ids(positions, start_after, limit, |position| {
+        songs.get(*position as usize - 1).cloned()
+    }))
+}
+
+// Helper function to check that a song filter's ranges are not empty
+fn validate_song_filter(filter: &SongFilter) -> Result<(), Error> {
+    if filter.genres.len() > MAX_FILTER_GENRES {
+        return Err(Error::InvalidPayload {
+            msg: format!("a filter may list at most {} genres", MAX_FILTER_GENRES),
+        });
+    }
+    if filter.mood_tags.len() > MAX_MOOD_TAGS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a filter may list at most {} mood tags", MAX_MOOD_TAGS),
+        });
+    }
+    validate_filter_range("year_from", filter.year_from, "year_to", filter.year_to)?;
+    validate_filter_range("min_price", filter.min_price, "max_price", filter.max_price)?;
+    validate_filter_range("bpm_from", filter.bpm_from, "bpm_to", filter.bpm_to)?;
+    validate_filter_range(
+        "min_duration_secs",
+        filter.min_duration_secs,
+        "max_duration_secs",
+        filter.max_duration_secs,
+    )?;
+    if let Some(isrc) = &filter.isrc {
+        normalize_isrc(isrc)?;
+    }
+    Ok(())
+}
+
+// Helper function to check that the lower bound of a filter range is not above its upper bound
+fn validate_filter_range(low_name: &str, low: Option<u32>, high_name: &str, high: Option<u32>) -> Result<(), Error> {
+    match (low, high) {
+        (Some(low), Some(high)) if low > high => Err(Error::InvalidPayload {
+            msg: format!("{}:{} is above {}:{}", low_name, low, high_name, high),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to load the songs that may meet a filter in id order,
+// reading the narrowest index the filter allows and every song when none applies
+fn filter_candidates(filter: &SongFilter) -> Vec<Song> {
+    let song_ids: BTreeSet<SongId> = if let Some(owner_id) = filter.owner_id {
+        index_ids(&OWNER_SONG_INDEX, owner_id).into_iter().collect()
+    } else if let Some(artist) = &filter.artist {
+        index_ids(&ARTIST_SONG_INDEX, IndexText::new(artist)).into_iter().collect()
+    } else if !filter.genres.is_empty() {
+        filter
+            .genres
+            .iter()
+            .flat_map(|genre| index_ids(&GENRE_SONG_INDEX, IndexText::new(genre)))
+            .collect()
+    } else if filter.year_from.is_some() || filter.year_to.is_some() {
+        let from = (filter.year_from.unwrap_or(0), SongId::default());
+        let to = (filter.year_to.unwrap_or(u32::MAX), SongId(u64::MAX));
+        YEAR_SONG_INDEX.with(|index| index.borrow().range(from..=to).map(|((_, song_id), _)| song_id).collect())
+    } else {
+        return SONG_STORAGE.with(|s| s.borrow().iter().map(|(_, song)| song).collect());
+    };
+    song_ids.iter().filter_map(_get_song).collect()
+}
+
+// Define query function to count the songs per genre, decade and price band,
+// reading the running counts for the whole catalog and counting the songs meeting a filter otherwise
+#[ic_cdk::query]
+fn get_catalog_facets(filter: Option<SongFilter>) -> Result<CatalogFacets, Error> {
+    let filter = filter.unwrap_or_default();
+    validate_song_filter(&filter)?;
+
+    let (total, genres, decades, price_bands) = match filter == SongFilter::default() {
+        true => (
+            SONG_STORAGE.with(|s| s.borrow().len()),
+            GENRE_COUNTS.with(|counts| counts.borrow().iter().collect()),
+            DECADE_COUNTS.with(|counts| counts.borrow().iter().collect()),
+            PRICE_BAND_COUNTS.with(|counts| counts.borrow().iter().collect()),
+        ),
+        false => {
+            let songs: Vec<Song> = filter_candidates(&filter)
+                .into_iter()
+                .filter(|song| filter.matches(song))
+                .collect();
+            (
+                songs.len() as u64,
+                count_values(songs.iter().map(genre_facet)),
+                count_values(songs.iter().map(decade_facet)),
+                count_values(songs.iter().map(price_band_facet)),
+            )
+        }
+    };
+
+    let mut genres: Vec<GenreCount> = genres
+        .into_iter()
+        .map(|(genre, count): (IndexText, u64)| GenreCount { genre: genre.0, count })
+        .collect();
+    genres.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.genre.cmp(&b.genre)));
+    Ok(CatalogFacets {
+        total,
+        genres,
+        decades: decades
+            .into_iter()
+            .map(|(decade, count)| DecadeCount { decade, count })
+            .collect(),
+        price_bands: price_bands
+            .into_iter()
+            .map(|(min_price, count)| PriceBandCount {
+                min_price,
+                max_price: PRICE_BAND_FLOORS
+                    .iter()
+                    .find(|floor| **floor > min_price)
+                    .map(|floor| floor - 1),
+                count,
+            })
+            .collect(),
+    })
+}
+
+// Facet values of a song, one function per facet
+fn genre_facet(song: &Song) -> IndexText {
+    IndexText::new(&song.genre)
+}
+
+fn decade_facet(song: &Song) -> u32 {
+    song.year / 10 * 10
+}
+
+fn price_band_facet(song: &Song) -> u32 {
+    PRICE_BAND_FLOORS
+        .iter()
+        .rev()
+        .find(|floor| **floor <= song.price)
+        .copied()
+        .unwrap_or_default()
+}
+
+// Helper function to count how many songs share each facet value
+fn count_values<K: Ord>(values: impl Iterator<Item = K>) -> BTreeMap<K, u64> {
+    let mut counts = BTreeMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Helper function to count the stored songs per value of a facet
+fn expected_counts<K: Ord>(facet: fn(&Song) -> K) -> BTreeMap<K, u64> {
+    SONG_STORAGE.with(|s| count_values(s.borrow().iter().map(|(_, song)| facet(&song))))
+}
+
+// Define update functions to update an existing song
+#[ic_cdk::update]
+fn update_song(payload: UpdateSongPayload) -> Result<Song, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the existing song based on the payload
+    let song = match _get_song(&payload.id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", payload.id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the song
+    let owner = match _get_owner(&song.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", song.owner_id),
+            })
+        }
+    };
+
+    // Check if the caller is the owner of the song
+    authorize_owner(&owner, "update")?;
+
+    // Refuse changes made against an older version of the song
+    if payload.version != song.version {
+        return Err(Error::VersionConflict {
+            msg: format!(
+                "song id:{} is at version:{}, the update was made against version:{}",
+                song.id, song.version, payload.version
+            ),
+            current_version: song.version,
+        });
+    }
+
+    // Check the new revenue split and recording codes before anything changes
+    validate_rights_holders(song.owner_id, &payload.rights_holders, payload.approval_quorum_bps)?;
+    validate_song_work(payload.work_id)?;
+    let isrc = match payload.isrc {
+        Some(isrc) => Some(normalize_isrc(&isrc)?),
+        None => None,
+    };
+    let availability = normalize_availability(payload.availability)?;
+    validate_song_timing(payload.duration_secs, payload.bpm)?;
+    let mood_tags = normalize_mood_tags(payload.mood_tags)?;
+    let language = normalize_language(payload.language)?;
+
+    // Create a new song with updated information
+    let mut new_song = song.clone();
+    new_song.title = payload.title.clone();
+    new_song.artist = payload.artist;
+    new_song.year = payload.year;
+    new_song.genre = payload.genre;
+    new_song.price = payload.price;
+    new_song.rights_holders = payload.rights_holders;
+    new_song.approval_quorum_bps = payload.approval_quorum_bps;
+    new_song.work_id = payload.work_id;
+    new_song.isrc = isrc;
+    new_song.availability = availability;
+    new_song.duration_secs = payload.duration_secs;
+    new_song.bpm = payload.bpm;
+    new_song.musical_key = payload.musical_key;
+    new_song.mood_tags = mood_tags;
+    new_song.language = language;
+    new_song.explicit = payload.explicit;
+    new_song.version = song.version + 1;
+
+    // Store the updated song, moving its index entries along
+    let mut staged = StagedWrites::default();
+    staged.insert_song(new_song.clone());
+    staged.commit();
+    Ok(new_song)
+}
+
+// Define update functions to change some fields of an existing song, checking them as update_song does
+#[ic_cdk::update]
+fn patch_song(payload: PatchSongPayload) -> Result<Song, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the existing song based on the payload
+    let song = match _get_song(&payload.id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", payload.id),
+            })
+        }
+    };
+
+    // Fill the fields left unset from the song, update_song checks the version and the caller
+    update_song(UpdateSongPayload {
+        id: payload.id,
+        version: payload.version,
+        title: payload.title.unwrap_or(song.title),
+        artist: payload.artist.unwrap_or(song.artist),
+        year: payload.year.unwrap_or(song.year),
+        genre: payload.genre.unwrap_or(song.genre),
+        price: payload.price.unwrap_or(song.price),
+        rights_holders: payload.rights_holders.unwrap_or(song.rights_holders),
+        approval_quorum_bps: payload.approval_quorum_bps.unwrap_or(song.approval_quorum_bps),
+        work_id: payload.work_id.unwrap_or(song.work_id),
+        isrc: payload.isrc.unwrap_or(song.isrc),
+        availability: payload.availability.unwrap_or(song.availability),
+        duration_secs: payload.duration_secs.unwrap_or(song.duration_secs),
+        bpm: payload.bpm.unwrap_or(song.bpm),
+        musical_key: payload.musical_key.unwrap_or(song.musical_key),
+        mood_tags: payload.mood_tags.unwrap_or(song.mood_tags),
+        language: payload.language.unwrap_or(song.language),
+        explicit: payload.explicit.unwrap_or(song.explicit),
+    })
+}
+
+// Define update functions to delete an existing song
+#[ic_cdk::update]
+fn delete_song(id: SongId) -> Result<Song, Error> {
+    // Retrieve the existing song based on the id
+    let song = match _get_song(&id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the song
+    let owner = match _get_owner(&song.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", song.owner_id),
+            })
+        }
+    };
+
+    // Check if the caller is the owner of the song
+    authorize_owner(&owner, "delete")?;
+
+    withdraw_song(&song)?;
+    Ok(song)
+}
+
+// Helper function to remove a song together with everything that lists it, nothing is written on error
+fn withdraw_song(song: &Song) -> Result<(), Error> {
+    // Remove the song from owner's list
+    let mut staged = StagedWrites::default();
+    match remove_song_from_owner(&mut staged, song) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    // Remove the song from licensee's list
+    match remove_song_from_licensee(&mut staged, song) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    // Withdraw the song's offer templates
+    for template_id in index_ids(&SONG_TEMPLATE_INDEX, song.id) {
+        staged.remove_template(template_id);
+    }
+
+    // Remove the song together with everything that listed it
+    staged.remove_song(song.id);
+    staged.commit();
+    Ok(())
+}
+
+// Helper function to check that the work a recording references exists
+fn validate_song_work(work_id: Option<WorkId>) -> Result<(), Error> {
+    match work_id {
+        Some(id) if _get_work(&id).is_none() => Err(Error::NotFound {
+            msg: format!("work id:{} could not be found, add the work first", id),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to check a song's availability, sorting its lists and dropping duplicates
+fn normalize_availability(availability: SongAvailability) -> Result<SongAvailability, Error> {
+    Ok(SongAvailability {
+        usages: sorted_unique(availability.usages),
+        media_channels: sorted_unique(availability.media_channels),
+        territory: normalize_territory(availability.territory)?,
+        exclusive: availability.exclusive,
+    })
+}
+
+// Helper function to check a song's length and tempo
+fn validate_song_timing(duration_secs: Option<u32>, bpm: Option<u32>) -> Result<(), Error> {
+    if let Some(duration_secs) = duration_secs {
+        if !(1..=MAX_DURATION_SECS).contains(&duration_secs) {
+            return Err(Error::InvalidPayload {
+                msg: format!("duration_secs:{} must be between 1 and {}", duration_secs, MAX_DURATION_SECS),
+            });
+        }
+    }
+    if let Some(bpm) = bpm {
+        if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+            return Err(Error::InvalidPayload {
+                msg: format!("bpm:{} must be between {} and {}", bpm, MIN_BPM, MAX_BPM),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Helper function to check a song's mood and theme tags, storing them trimmed, lowercased and sorted
+fn normalize_mood_tags(tags: Vec<String>) -> Result<Vec<String>, Error> {
+    let mut normalized = BTreeSet::new();
+    for tag in tags {
+        let key = normalize_index_text(&tag);
+        if key.is_empty() || key.chars().count() > MAX_MOOD_TAG_CHARS {
+            return Err(Error::InvalidPayload {
+                msg: format!("mood tag:{} must hold between 1 and {} characters", tag, MAX_MOOD_TAG_CHARS),
+            });
+        }
+        normalized.insert(key);
+    }
+
+    if normalized.len() > MAX_MOOD_TAGS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a song may carry at most {} mood tags", MAX_MOOD_TAGS),
+        });
+    }
+    Ok(normalized.into_iter().collect())
+}
+
+// Helper function to check a song's language code, storing it lowercase
+fn normalize_language(language: Option<String>) -> Result<Option<String>, Error> {
+    let Some(code) = language else {
+        return Ok(None);
+    };
+    let language = code.trim().to_ascii_lowercase();
+    if language.len() != 2 || !ISO_639_1.split_whitespace().any(|l| l == language) {
+        return Err(Error::InvalidPayload {
+            msg: format!("language:{} is not an ISO 639-1 language code", code),
+        });
+    }
+    Ok(Some(language))
+}
+
+// Helper function to sort a list and drop its duplicates
+fn sorted_unique<T: Ord>(items: Vec<T>) -> Vec<T> {
+    let set: BTreeSet<T> = items.into_iter().collect();
+    set.into_iter().collect()
+}
+
+// Helper function to check a territory's country codes, storing them uppercase and sorted
+fn normalize_territory(territory: Territory) -> Result<Territory, Error> {
+    let codes = match territory {
+        Territory::Worldwide => return Ok(Territory::Worldwide),
+        Territory::Countries(codes) => codes,
+    };
+
+    let mut countries = BTreeSet::new();
+    for code in codes {
+        let country = code.trim().to_ascii_uppercase();
+        if country.len() != 2 || !ISO_3166_ALPHA_2.split_whitespace().any(|c| c == country) {
+            return Err(Error::InvalidPayload {
+                msg: format!("territory:{} is not an ISO 3166-1 alpha-2 country code", code),
+            });
+        }
+        countries.insert(country);
+    }
+
+    if countries.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "a territory needs at least one country, or use Worldwide".to_string(),
+        });
+    }
+
+    Ok(Territory::Countries(countries.into_iter().collect()))
+}
+
+// Helper function to check whether one territory lies entirely within another
+fn territory_within(inner: &Territory, outer: &Territory) -> bool {
+    match (inner, outer) {
+        (_, Territory::Worldwide) => true,
+        (Territory::Worldwide, Territory::Countries(_)) => false,
+        (Territory::Countries(inner), Territory::Countries(outer)) => {
+            inner.iter().all(|country| outer.contains(country))
+        }
+    }
+}
+
+// Helper function to check a license's scope against what its song makes available
+fn validate_license_scope(
+    song: &Song,
+    usages: &[UsageType],
+    media_channels: &[MediaChannel],
+    territory: &Territory,
+    exclusive: bool,
+) -> Result<(), Error> {
+    let available = &song.availability;
+
+    if usages.is_empty() || media_channels.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "a license needs at least one usage type and one media channel".to_string(),
+        });
+    }
+
+    if let Some(usage) = usages.iter().find(|usage| !available.usages.contains(usage)) {
+        return Err(Error::InvalidPayload {
+            msg: format!("song id:{} is not available for {:?} use", song.id, usage),
+        });
+    }
+
+    if let Some(channel) = media_channels
+        .iter()
+        .find(|channel| !available.media_channels.contains(channel))
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("song id:{} is not available on {:?}", song.id, channel),
+        });
+    }
+
+    if !territory_within(territory, &available.territory) {
+        return Err(Error::InvalidPayload {
+            msg: format!("song id:{} is not available in the whole requested territory", song.id),
+        });
+    }
+
+    if exclusive && !available.exclusive {
+        return Err(Error::InvalidPayload {
+            msg: format!("song id:{} is not available for exclusive licenses", song.id),
+        });
+    }
+
+    Ok(())
+}
+
+// Helper function to normalize an ISRC such as "US-S1Z-99-00001" to "USS1Z9900001"
+fn normalize_isrc(isrc: &str) -> Result<String, Error> {
+    let code: String = isrc
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let bytes = code.as_bytes();
+
+    // Country code, registrant code, year of reference and designation code
+    let valid = bytes.len() == 12
+        && bytes[..2].iter().all(u8::is_ascii_uppercase)
+        && bytes[2..5].iter().all(u8::is_ascii_alphanumeric)
+        && bytes[5..].iter().all(u8::is_ascii_digit);
+
+    match valid {
+        true => Ok(code),
+        false => Err(Error::InvalidPayload {
+            msg: format!("isrc:{} is not a valid International Standard Recording Code", isrc),
+        }),
+    }
+}
+
+// Helper function to normalize an ISWC such as "T-034.524.680-1" to "T0345246801",
+// checking its check digit
+fn normalize_iswc(iswc: &str) -> Result<String, Error> {
+    let code: String = iswc
+        .chars()
+        .filter(|c| !matches!(c, '-' | '.'))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    let digits: Vec<u32> = match code.strip_prefix('T') {
+        Some(rest) if rest.len() == 10 => rest.chars().filter_map(|c| c.to_digit(10)).collect(),
+        _ => Vec::new(),
+    };
+
+    // The check digit makes 1 plus the position-weighted sum of the work digits a multiple of 10
+    let valid = digits.len() == 10 && {
+        let sum: u32 = 1 + digits[..9]
+            .iter()
+            .zip(1..)
+            .map(|(digit, weight)| digit * weight)
+            .sum::<u32>();
+        (10 - sum % 10) % 10 == digits[9]
+    };
+
+    match valid {
+        true => Ok(code),
+        false => Err(Error::InvalidPayload {
+            msg: format!("iswc:{} is not a valid International Standard Musical Work Code", iswc),
+        }),
+    }
+}
+
+// Helper function to check a song's revenue split and approval quorum
+fn validate_rights_holders(owner_id: OwnerId, holders: &[RightsHolder], quorum_bps: u64) -> Result<(), Error> {
+    if holders.is_empty() || holders.len() > MAX_RIGHTS_HOLDERS {
+        return Err(Error::InvalidPayload {
+            msg: format!("a song needs between 1 and {} rights holders", MAX_RIGHTS_HOLDERS),
+        });
+    }
+
+    // The managing owner keeps a share so they answer for the song
+    if !holders.iter().any(|holder| holder.owner_id == owner_id) {
+        return Err(Error::InvalidPayload {
+            msg: format!("owner id:{} must be one of the song's rights holders", owner_id),
+        });
+    }
+
+    let mut seen = BTreeSet::new();
+    for holder in holders {
+        if !seen.insert(holder.owner_id) {
+            return Err(Error::InvalidPayload {
+                msg: format!("owner id:{} is listed as a rights holder more than once", holder.owner_id),
+            });
+        }
+
+        if holder.share_bps == 0 || holder.share_bps > BPS_DENOMINATOR {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "owner id:{} must hold between 1 and {} basis points",
+                    holder.owner_id, BPS_DENOMINATOR
+                ),
+            });
+        }
+
+        if _get_owner(&holder.owner_id).is_none() {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found, add ownwer first", holder.owner_id),
+            });
+        }
+    }
+
+    let total: u64 = holders.iter().map(|holder| holder.share_bps).sum();
+    if total != BPS_DENOMINATOR {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "rights holder shares add up to {} basis points instead of {}",
+                total, BPS_DENOMINATOR
+            ),
+        });
+    }
+
+    if quorum_bps == 0 || quorum_bps > BPS_DENOMINATOR {
+        return Err(Error::InvalidPayload {
+            msg: format!("approval quorum must be between 1 and {} basis points", BPS_DENOMINATOR),
+        });
+    }
+
+    Ok(())
+}
+
+// Define query functions to get works by id
+#[ic_cdk::query]
+fn get_work(id: WorkId) -> Result<Work, Error> {
+    match _get_work(&id) {
+        Some(work) => Ok(work),
+        None => Err(Error::NotFound {
+            msg: format!("work id:{} could not be found", id),
+        }),
+    }
+}
+
+// Helper function to get a work by id
+fn _get_work(id: &WorkId) -> Option<Work> {
+    WORK_STORAGE.with(|s| s.borrow().get(id))
+}
+
+// Define update functions to create new works
+#[ic_cdk::update]
+fn create_work(payload: WorkPayload) -> Result<Work, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    let owner = match _get_owner(&payload.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found, add ownwer first", payload.owner_id),
+            })
+        }
+    };
+
+    // Only the owner can add works to their catalog
+    authorize_owner(&owner, "add works")?;
+
+    // Check the revenue split and the work code before the work is created
+    validate_rights_holders(payload.owner_id, &payload.rights_holders, payload.approval_quorum_bps)?;
+    let iswc = normalize_iswc(&payload.iswc)?;
+
+    // Allocate the next work id now that the request is valid
+    let id = WorkId(next_id(|sequences| &mut sequences.works));
+
+    // Create a new work based on the provided payload
+    let work = Work {
+        id,
+        title: payload.title.clone(),
+        iswc,
+        owner_id: payload.owner_id,
+        rights_holders: payload.rights_holders,
+        approval_quorum_bps: payload.approval_quorum_bps,
+    };
+
+    // Refuse to overwrite an existing work
+    if _get_work(&id).is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("work title:{} could not be created", payload.title),
+        });
+    }
+
+    // Store the new work together with its index entries
+    let mut staged = StagedWrites::default();
+    staged.insert_work(work.clone());
+    staged.commit();
+    Ok(work)
+}
+
+// Define update functions to update an existing work
+#[ic_cdk::update]
+fn update_work(payload: UpdateWorkPayload) -> Result<Work, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the existing work based on the payload
+    let work = match _get_work(&payload.id) {
+        Some(work) => work,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("work id:{} could not be found", payload.id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the work
+    let owner = match _get_owner(&work.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", work.owner_id),
+            })
+        }
+    };
+
+    // Check if the caller is the owner of the work
+    authorize_owner(&owner, "update")?;
+
+    // Check the new revenue split and work code before anything changes
+    validate_rights_holders(work.owner_id, &payload.rights_holders, payload.approval_quorum_bps)?;
+    let iswc = normalize_iswc(&payload.iswc)?;
+
+    // Create a new work with updated information
+    let mut new_work = work.clone();
+    new_work.title = payload.title.clone();
+    new_work.iswc = iswc;
+    new_work.rights_holders = payload.rights_holders;
+    new_work.approval_quorum_bps = payload.approval_quorum_bps;
+
+    // Store the updated work, moving its index entries along
+    let mut staged = StagedWrites::default();
+    staged.insert_work(new_work.clone());
+    staged.commit();
+    Ok(new_work)
+}
+
+// Helper function to get an owner by id
+fn _get_owner(id: &OwnerId) -> Option<Owner> {
+    OWNER_STORAGE.with(|s| s.borrow().get(id))
+}
+
+// Helper function to add a song to an owner's index
+fn add_song_to_owner(staged: &mut StagedWrites, owner_id: OwnerId, song_id: SongId) -> Result<(), Error> {
+    // Check that the owner exists
+    if _get_owner(&owner_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("owner id:{} could not be found", owner_id),
+        });
+    }
+
+    staged.link_owner_song(owner_id, song_id, true);
+    Ok(())
+}
+
+// Define update function to create a new owner
+#[ic_cdk::update]
+fn create_owner(payload: OwnerPayload) -> Result<Owner, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // The owner record is bound to the calling principal
+    let caller = _get_caller()?;
+
+    // Allocate the next owner id now that the request is valid
+    let id = OwnerId(next_id(|sequences| &mut sequences.owners));
+
+    // Create a new owner instance
+    let owner = Owner {
+        id,
+        name: payload.name.clone(),
+        email: payload.email.clone(),
+        principal: Some(caller),
+        auth_key: None,
+    };
+
+    // Insert the owner into the storage and handle potential errors
+    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(id, owner.clone())) {
+        None => Ok(owner),
+        Some(_) => Err(Error::InvalidPayload {
+            msg: format!("owner name:{} could not be created", payload.name),
+        }),
+    }
+}
+
+// Define update function to bind a legacy owner record to the caller
+#[ic_cdk::update]
+fn claim_owner(payload: ClaimOwnerPayload) -> Result<Owner, Error> {
+    let caller = _get_caller()?;
+
+    // Retrieve the owner to be claimed
+    let mut owner = match _get_owner(&payload.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", payload.owner_id),
+            })
+        }
+    };
+
+    // Owners created by a principal cannot be claimed again
+    if owner.principal.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: format!("owner id:{} has already been claimed", payload.owner_id),
+        });
+    }
+
+    // The legacy auth key proves ownership one last time
+    if owner.auth_key.as_deref() != Some(payload.auth_key.as_str()) {
+        return Err(Error::Unauthorized {
+            msg: format!("auth key is invalid for owner id:{}", payload.owner_id),
+        });
+    }
+
+    // Bind the owner to the caller and drop the shared secret
+    owner.principal = Some(caller);
+    owner.auth_key = None;
+
+    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(owner.id, owner.clone())) {
+        Some(_) => Ok(owner),
+        None => Err(Error::InvalidPayload {
+            msg: format!("owner id:{} could not be claimed", payload.owner_id),
+        }),
+    }
+}
+
+// Helper function to take the next id from one of the entity sequences
+fn next_id(sequence: impl FnOnce(&mut IdSequences) -> &mut u64) -> u64 {
+    ID_SEQUENCES.with(|cell| {
+        let mut sequences = cell.borrow().get().clone();
+        let next = sequence(&mut sequences);
+        let id = *next;
+        *next += 1;
+        cell.borrow_mut()
+            .set(sequences)
+            .expect("Cannot increment Ids");
+        id
+    })
+}
+
+// Helper function to get the caller, rejecting the anonymous principal
+fn _get_caller() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "anonymous callers are not allowed, sign in first".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+// Helper function to check that the caller is the given owner
+fn authorize_owner(owner: &Owner, action: &str) -> Result<(), Error> {
+    match owner.principal {
+        Some(principal) if principal == ic_cdk::caller() => Ok(()),
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("caller is not owner id:{}, only song owner can {}", owner.id, action),
+        }),
+        None => Err(Error::Unauthorized {
+            msg: format!("owner id:{} has not been claimed yet, call claim_owner first", owner.id),
+        }),
+    }
+}
+
+// Define query function to get a license by ID
+#[ic_cdk::query]
+fn get_license(id: LicenseId) -> Result<License, Error> {
+    let license = match _get_license(&id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Only the parties to the license and controllers can view it
+    authorize_license_party(&license, "view it")?;
+    Ok(license)
+}
+
+// Helper function to check that the caller is a controller, the licensee or a rights holder of a license
+fn authorize_license_party(license: &License, action: &str) -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Ok(());
+    }
+    let caller_is_licensee = _get_licensee(&license.licensee_id)
+        .and_then(|licensee| licensee.principal)
+        .is_some_and(|principal| principal == ic_cdk::caller());
+    if caller_is_licensee {
+        return Ok(());
+    }
+
+    let (holders, _) = license_rights(license);
+    let owner_ids = std::iter::once(license.owner_id).chain(holders.iter().map(|holder| holder.owner_id));
+    match caller_owner_id(owner_ids) {
+        Some(_) => Ok(()),
+        None => Err(Error::Unauthorized {
+            msg: format!(
+                "caller is not a party to license id:{}, only its licensee and rights holders can {}",
+                license.id, action
+            ),
+        }),
+    }
+}
+
+// Define query function to get license requests for a specific owner
+#[ic_cdk::query]
+fn get_owner_license_requests(
+    id: OwnerId,
+    status: Option<LicenseStatus>,
+    limit: Option<u32>,
+    start_after: Option<LicenseId>,
+) -> Result<Page<License, LicenseId>, Error> {
+    let limit = page_limit(limit)?;
+
+    // Retrieve the owner whose requests are listed
+    let owner = match _get_owner(&id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Only the owner and controllers can view the owner's license requests
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        authorize_owner(&owner, "view its license requests")?;
+    }
+
+    // Gather the licenses the owner manages and the licenses on recordings they hold a share of,
+    // directly or through the recording's work
+    let mut license_ids: BTreeSet<LicenseId> = index_ids(&OWNER_REQUEST_INDEX, id).into_iter().collect();
+    let mut song_ids: BTreeSet<SongId> = index_ids(&HOLDER_SONG_INDEX, id).into_iter().collect();
+    for work_id in index_ids(&HOLDER_WORK_INDEX, id) {
+        song_ids.extend(index_ids(&WORK_SONG_INDEX, work_id));
+    }
+    for song_id in song_ids {
+        license_ids.extend(index_ids(&SONG_LICENSE_INDEX, song_id));
+    }
+
+    // Keep licenses for the specified owner ID and optional status,
+    // licenses on rights the owner holds a share of wait on their approval too
+    let page = page_of_matching_ids(license_ids.into_iter().collect(), start_after, limit, |license_id| {
+        _get_license(license_id).filter(|license| {
+            let is_holder = license.owner_id == id
+                || license_rights(license).0.iter().any(|holder| holder.owner_id == id);
+            is_holder && status.is_none_or(|status| license.status == status)
+        })
+    });
+
+    // Handle cases where no licenses are found or return the result
+    match page.total {
+        0 => Err(Error::NotFound {
+            msg: format!("no licenses could be found for owner id:{}", id),
+        }),
+        _ => Ok(page),
+    }
+}
+
+// Define query function to get licenses for a specific licensee
+#[ic_cdk::query]
+fn get_licensee_licenses(
+    id: LicenseeId,
+    limit: Option<u32>,
+    start_after: Option<LicenseId>,
+) -> Result<Page<License, LicenseId>, Error> {
+    let limit = page_limit(limit)?;
+
+    // Retrieve the licensee whose licenses are requested
+    let licensee = match _get_licensee(&id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Only the licensee can view their own licenses
+    authorize_licensee(&licensee, "view their licenses")?;
+
+    // Retrieve the licensee's license ids from its range of the index
+    let license_ids = index_ids(&LICENSEE_REQUEST_INDEX, id);
+
+    // Handle cases where no licenses are found or return the result
+    match license_ids.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no licenses could be found for licensee id:{}", id),
+        }),
+        _ => Ok(page_of_ids(license_ids, start_after, limit, _get_license)),
+    }
+}
+
+// Define update function to create a new license request
+#[ic_cdk::update]
+async fn create_license_request(payload: LicensePayload) -> Result<License, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Validate the requested license term
+    if let Err(e) = validate_license_dates(payload.start_date, payload.end_date) {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // validate licensee
+    let licensee = match _get_licensee(&payload.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found, add them first", payload.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can request licenses in their own name
+    authorize_licensee(&licensee, "request licenses")?;
+
+    // Retrieve the corresponding song for the license request
+    let song = match _get_song(&payload.song_id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", payload.song_id),
+            })
+        }
+    };
+
+    // The requested scope must be within what the owner made available
+    let territory = normalize_territory(payload.territory)?;
+    let usages = sorted_unique(payload.usages);
+    let media_channels = sorted_unique(payload.media_channels);
+    validate_license_scope(&song, &usages, &media_channels, &territory, payload.exclusive)?;
+
+    // Composition rights are granted by the managing owner of the song's work
+    let owner_id = right_owner_id(&song, payload.right)?;
+
+    // The price must cover the ledger fee before a license id is taken
+    let quote = quote_license_fee(payload.price).await?;
+
+    // Allocate the next license id now that the request is valid
+    let id = LicenseId(next_id(|sequences| &mut sequences.licenses));
+
+    // Escrow the offered price before the request is filed
+    let payment = escrow_license_fee(id, ic_cdk::caller(), quote).await?;
+
+    // Create a new license instance
+    let license = License {
+        id,
+        song_id: payload.song_id,
+        owner_id,
+        licensee_id: payload.licensee_id,
+        right: payload.right,
+        usages,
+        media_channels,
+        territory,
+        exclusive: payload.exclusive,
+        status: LicenseStatus::Requested,
+        price: 0,
+        terms: String::new(),
+        start_date: payload.start_date,
+        end_date: payload.end_date,
+        status_history: vec![StatusChange {
+            status: LicenseStatus::Requested,
+            timestamp: ic_cdk::api::time(),
+        }],
+        offers: vec![Offer {
+            party: OfferParty::Licensee,
+            price: payload.price,
+            terms: payload.terms,
+            timestamp: ic_cdk::api::time(),
+        }],
+        payments: payment.into_iter().collect(),
+        approvals: Vec::new(),
+        royalties: Vec::new(),
+        template_id: None,
+        renews: None,
+        transfer_policy: payload.transfer_policy,
+        pending_transfer: None,
+        transfers: Vec::new(),
+    };
+
+    // Store the license request together with its index entries
+    let mut staged = StagedWrites::default();
+    staged.insert_license(license.clone());
+    staged.commit();
+    Ok(license)
+}
+
+// Define query function to get the offer templates published for a song
+#[ic_cdk::query]
+fn get_song_offer_templates(song_id: SongId) -> Result<Vec<OfferTemplate>, Error> {
+    let templates: Vec<OfferTemplate> = index_ids(&SONG_TEMPLATE_INDEX, song_id)
+        .iter()
+        .filter_map(_get_offer_template)
+        .collect();
+
+    // Handle cases where no templates are found or return the result
+    match templates.len() {
+        0 => Err(Error::NotFound {
+            msg: format!("no offer templates could be found for song id:{}", song_id),
+        }),
+        _ => Ok(templates),
+    }
+}
+
+// Helper function to retrieve an offer template by ID
+fn _get_offer_template(id: &TemplateId) -> Option<OfferTemplate> {
+    TEMPLATE_STORAGE.with(|s| s.borrow().get(id))
+}
+
+// Define update function for an owner to publish an offer template for a song
+#[ic_cdk::update]
+fn create_offer_template(payload: OfferTemplatePayload) -> Result<OfferTemplate, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the song the template is published for
+    let song = match _get_song(&payload.song_id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", payload.song_id),
+            })
+        }
+    };
+
+    // Only the owner granting the right can publish offers for it
+    let owner_id = right_owner_id(&song, payload.right)?;
+    let owner = match _get_owner(&owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", owner_id),
+            })
+        }
+    };
+    authorize_owner(&owner, "publish offer templates")?;
+
+    // The template must offer what the song makes available, for a term licenses may run
+    let territory = normalize_territory(payload.territory)?;
+    let usages = sorted_unique(payload.usages);
+    let media_channels = sorted_unique(payload.media_channels);
+    validate_license_scope(&song, &usages, &media_channels, &territory, payload.exclusive)?;
+
+    let max_duration = MAX_LICENSE_DURATION.with(|d| *d.borrow().get());
+    if payload.duration == 0 || payload.duration > max_duration {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "template duration must be between 1 nanosecond and {} days",
+                max_duration / NANOS_PER_DAY
+            ),
+        });
+    }
+
+    // Allocate the next template id now that the request is valid
+    let id = TemplateId(next_id(|sequences| &mut sequences.templates));
+
+    let template = OfferTemplate {
+        id,
+        song_id: payload.song_id,
+        owner_id,
+        right: payload.right,
+        usages,
+        media_channels,
+        territory,
+        exclusive: payload.exclusive,
+        duration: payload.duration,
+        price: payload.price,
+        terms: payload.terms,
+        auto_approve: payload.auto_approve,
+        transfer_policy: Some(payload.transfer_policy),
+    };
+
+    // Insert the template into storage together with its index entry
+    let mut staged = StagedWrites::default();
+    staged.insert_template(template.clone());
+    staged.commit();
+    Ok(template)
+}
+
+// Define update function for an owner to withdraw an offer template
+#[ic_cdk::update]
+fn delete_offer_template(id: TemplateId) -> Result<OfferTemplate, Error> {
+    // Retrieve the template to be withdrawn
+    let template = match _get_offer_template(&id) {
+        Some(template) => template,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("offer template id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Retrieve the owner who published the template
+    let owner = match _get_owner(&template.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", template.owner_id),
+            })
+        }
+    };
+
+    // Only the publisher can withdraw the template, licenses requested against it are kept
+    authorize_owner(&owner, "withdraw offer templates")?;
+
+    let mut staged = StagedWrites::default();
+    staged.remove_template(id);
+    staged.commit();
+    Ok(template)
+}
+
+// Define update function to request a license on the terms of an offer template
+#[ic_cdk::update]
+async fn request_template_license(payload: TemplateLicensePayload) -> Result<License, Error> {
+    // Retrieve the template the licensee accepts
+    let template = match _get_offer_template(&payload.template_id) {
+        Some(template) => template,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("offer template id:{} could not be found", payload.template_id),
+            })
+        }
+    };
+
+    // validate licensee
+    let licensee = match _get_licensee(&payload.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found, add them first", payload.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can request licenses in their own name
+    authorize_licensee(&licensee, "request licenses")?;
+
+    // Retrieve the song the template is published for
+    let song = match _get_song(&template.song_id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", template.song_id),
+            })
+        }
+    };
+
+    // The song's availability may have narrowed since the template was published
+    validate_license_scope(
+        &song,
+        &template.usages,
+        &template.media_channels,
+        &template.territory,
+        template.exclusive,
+    )?;
+
+    // Validate the license term the template sets from the start date
+    let end_date = payload.start_date.saturating_add(template.duration);
+    if let Err(e) = validate_license_dates(payload.start_date, end_date) {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    let owner_id = right_owner_id(&song, template.right)?;
+
+    // The price must cover the ledger fee before a license id is taken
+    let quote = quote_license_fee(template.price).await?;
+
+    // Allocate the next license id now that the request is valid
+    let id = LicenseId(next_id(|sequences| &mut sequences.licenses));
+
+    // Escrow the template price before the request is filed
+    let payment = escrow_license_fee(id, ic_cdk::caller(), quote).await?;
+
+    // Create a new license instance with the template's offer
+    let mut license = License {
+        id,
+        song_id: template.song_id,
+        owner_id,
+        licensee_id: payload.licensee_id,
+        right: template.right,
+        usages: template.usages,
+        media_channels: template.media_channels,
+        territory: template.territory,
+        exclusive: template.exclusive,
+        status: LicenseStatus::Requested,
+        price: 0,
+        terms: String::new(),
+        start_date: payload.start_date,
+        end_date,
+        status_history: vec![StatusChange {
+            status: LicenseStatus::Requested,
+            timestamp: ic_cdk::api::time(),
+        }],
+        offers: vec![Offer {
+            party: OfferParty::Licensee,
+            price: template.price,
+            terms: template.terms,
+            timestamp: ic_cdk::api::time(),
+        }],
+        payments: payment.into_iter().collect(),
+        approvals: Vec::new(),
+        royalties: Vec::new(),
+        template_id: Some(template.id),
+        renews: None,
+        transfer_policy: template.transfer_policy.unwrap_or_default(),
+        pending_transfer: None,
+        transfers: Vec::new(),
+    };
+
+    // Publishing an auto-approve template approves every request against it for the publisher
+    if template.auto_approve {
+        license.approvals.push(template.owner_id);
+    }
+
+    // Store the license request together with its index entries
+    let mut staged = StagedWrites::default();
+    staged.insert_license(license.clone());
+    staged.commit();
+
+    // Co-holders may still need to approve when the publisher's share is below the quorum
+    let (holders, quorum_bps) = license_rights(&license);
+    if !template.auto_approve || approved_bps(&holders, &license.approvals) < quorum_bps {
+        return Ok(license);
+    }
+
+    // An approval that fails, such as one clashing with an exclusive license, returns its error
+    // while the filed request keeps its escrow and waits for the rights holders
+    accept_latest_offer(license).await
+}
+
+// Define update function for a licensee to request the renewal of an approved license
+#[ic_cdk::update]
+async fn request_renewal(payload: RenewalPayload) -> Result<License, Error> {
+    // Retrieve the license to be renewed
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the licensee who holds the license
+    let licensee = match _get_licensee(&license.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found", license.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can renew their own license
+    authorize_licensee(&licensee, "renew their licenses")?;
+
+    // Only licenses still running can be renewed
+    if license.status != LicenseStatus::Approved {
+        return Err(Error::InvalidTransition {
+            msg: format!("license id:{} is {:?}, only approved licenses can be renewed", license.id, license.status),
+        });
+    }
+
+    // A license is renewed by one renewal at a time
+    // Renewals are on the same song, so only the song's licenses are looked at
+    let pending: Vec<LicenseId> = _get_song_licenses(license.song_id)
+        .into_iter()
+        .filter(|renewal| renewal.renews == Some(license.id))
+        .filter(|renewal| {
+            matches!(
+                renewal.status,
+                LicenseStatus::Requested | LicenseStatus::CounterOffered | LicenseStatus::Approved
+            )
         })
-        .expect("Cannot increment Ids");
+        .map(|renewal| renewal.id)
+        .collect();
+    if let Some(renewal_id) = pending.first() {
+        return Err(Error::InvalidPayload {
+            msg: format!("license id:{} is already renewed by license id:{}", license.id, renewal_id),
+        });
+    }
+
+    // Validate the renewed term, which picks up where the license ends
+    if let Err(e) = validate_license_dates(license.end_date, payload.end_date) {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the licensed song, whose availability may have narrowed since
+    let song = match _get_song(&license.song_id) {
+        Some(song) => song,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("song id:{} could not be found", license.song_id),
+            })
+        }
+    };
+    validate_license_scope(
+        &song,
+        &license.usages,
+        &license.media_channels,
+        &license.territory,
+        license.exclusive,
+    )?;
+    let owner_id = right_owner_id(&song, license.right)?;
+
+    // The price must cover the ledger fee before a license id is taken
+    let quote = quote_license_fee(license.price).await?;
+
+    // Allocate the next license id now that the request is valid
+    let id = LicenseId(next_id(|sequences| &mut sequences.licenses));
+
+    // The renewal is offered at the current price, the owner approves it or prices it anew
+    let payment = escrow_license_fee(id, ic_cdk::caller(), quote).await?;
+
+    let renewal = License {
+        id,
+        song_id: license.song_id,
+        owner_id,
+        licensee_id: license.licensee_id,
+        right: license.right,
+        usages: license.usages.clone(),
+        media_channels: license.media_channels.clone(),
+        territory: license.territory.clone(),
+        exclusive: license.exclusive,
+        status: LicenseStatus::Requested,
+        price: 0,
+        terms: String::new(),
+        start_date: license.end_date,
+        end_date: payload.end_date,
+        status_history: vec![StatusChange {
+            status: LicenseStatus::Requested,
+            timestamp: ic_cdk::api::time(),
+        }],
+        offers: vec![Offer {
+            party: OfferParty::Licensee,
+            price: license.price,
+            terms: license.terms.clone(),
+            timestamp: ic_cdk::api::time(),
+        }],
+        payments: payment.into_iter().collect(),
+        approvals: Vec::new(),
+        royalties: Vec::new(),
+        template_id: None,
+        renews: Some(license.id),
+        transfer_policy: license.transfer_policy,
+        pending_transfer: None,
+        transfers: Vec::new(),
+    };
+
+    // Store the renewal request together with its index entries
+    let mut staged = StagedWrites::default();
+    staged.insert_license(renewal.clone());
+    staged.commit();
+    Ok(renewal)
+}
+
+// Define query function to get every license in a license's renewal lineage, oldest first
+#[ic_cdk::query]
+fn get_license_lineage(id: LicenseId) -> Result<Vec<License>, Error> {
+    let license = match _get_license(&id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", id),
+            })
+        }
+    };
+
+    // Follow the renewed licenses back to the original deal
+    let mut root = license;
+    while let Some(previous) = root.renews.and_then(|previous_id| _get_license(&previous_id)) {
+        root = previous;
+    }
+
+    // Collect every renewal request made since, including declined ones
+    let mut lineage = vec![root];
+    let mut lineage_ids = BTreeSet::from([lineage[0].id]);
+    let licenses: Vec<License> = _get_song_licenses(lineage[0].song_id)
+        .into_iter()
+        .filter(|license| license.renews.is_some())
+        .collect();
+    // Renewals always have larger ids than the licenses they renew
+    for license in licenses {
+        if license.renews.is_some_and(|previous_id| lineage_ids.contains(&previous_id)) {
+            lineage_ids.insert(license.id);
+            lineage.push(license);
+        }
+    }
+
+    Ok(lineage)
+}
+
+// Define update function for a licensee to hand an approved license to another licensee
+#[ic_cdk::update]
+fn transfer_license(payload: TransferPayload) -> Result<License, Error> {
+    // Retrieve the license to be transferred
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the licensee who holds the license
+    let licensee = match _get_licensee(&license.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found", license.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can hand over their license
+    authorize_licensee(&licensee, "transfer their licenses")?;
+
+    if license.status != LicenseStatus::Approved {
+        return Err(Error::InvalidTransition {
+            msg: format!("license id:{} is {:?}, only approved licenses can be transferred", license.id, license.status),
+        });
+    }
+
+    if payload.licensee_id == license.licensee_id {
+        return Err(Error::InvalidPayload {
+            msg: format!("license id:{} is already held by licensee id:{}", license.id, payload.licensee_id),
+        });
+    }
+
+    // The receiving licensee must exist before anything changes
+    if _get_licensee(&payload.licensee_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("licensee id:{} could not be found", payload.licensee_id),
+        });
+    }
+
+    match license.transfer_policy {
+        TransferPolicy::NotTransferable => Err(Error::Unauthorized {
+            msg: format!("license id:{} terms do not permit transfer", license.id),
+        }),
+        TransferPolicy::Transferable => complete_license_transfer(license, payload.licensee_id),
+        // Wait for the owner, a later request replaces this one
+        TransferPolicy::OwnerConsent => {
+            let mut new_license = license.clone();
+            new_license.pending_transfer = Some(payload.licensee_id);
+            LICENSE_STORAGE.with(|s| s.borrow_mut().insert(license.id, new_license.clone()));
+            Ok(new_license)
+        }
+    }
+}
+
+// Define update function for the owner to consent to a pending license transfer
+#[ic_cdk::update]
+fn consent_license_transfer(payload: ProtectedPayload) -> Result<License, Error> {
+    // Retrieve the license being transferred
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the license
+    let owner = match _get_owner(&license.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", license.owner_id),
+            })
+        }
+    };
+
+    // Validate the authenticity of the consent
+    authorize_owner(&owner, "consent to transfers")?;
+
+    let to_licensee_id = match license.pending_transfer {
+        Some(licensee_id) if license.status == LicenseStatus::Approved => licensee_id,
+        _ => {
+            return Err(Error::InvalidPayload {
+                msg: format!("license id:{} has no pending transfer", payload.license_id),
+            })
+        }
+    };
+
+    // The receiving licensee must still exist before anything changes
+    if _get_licensee(&to_licensee_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("licensee id:{} could not be found", to_licensee_id),
+        });
+    }
+
+    complete_license_transfer(license, to_licensee_id)
+}
+
+// Helper function to move a license between licensees and record the transfer
+fn complete_license_transfer(license: License, to_licensee_id: LicenseeId) -> Result<License, Error> {
+    let from_licensee_id = license.licensee_id;
+
+    let mut staged = StagedWrites::default();
+    remove_license_from_licensee(&mut staged, from_licensee_id, license.id)?;
+    add_license_to_licensee(&mut staged, to_licensee_id, license.id)?;
+
+    let mut new_license = license;
+    new_license.licensee_id = to_licensee_id;
+    new_license.pending_transfer = None;
+    new_license.transfers.push(LicenseTransfer {
+        from_licensee_id,
+        to_licensee_id,
+        timestamp: ic_cdk::api::time(),
+    });
+
+    // Both licensee lists and the license are written together
+    staged.insert_license(new_license.clone());
+    staged.commit();
+    Ok(new_license)
+}
+
+// Define update function to approve a license
+#[ic_cdk::update]
+async fn approve_license(payload: ApprovePayload) -> Result<License, Error> {
+    // Retrieve the license to be approved
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Any rights holder of the song can approve
+    let (holders, quorum_bps) = license_rights(&license);
+    let approver = match caller_owner_id(holders.iter().map(|holder| holder.owner_id)) {
+        Some(owner_id) => owner_id,
+        None => {
+            return Err(Error::Unauthorized {
+                msg: format!(
+                    "caller holds no {:?} rights in song id:{}, only rights holders can approve",
+                    license.right, license.song_id
+                ),
+            })
+        }
+    };
+
+    // Check if the license has already been approved
+    if license.status == LicenseStatus::Approved {
+        return Err(Error::AlreadyApproved {
+            msg: format!(
+                "license id:{} has already been approved",
+                payload.license_id
+            ),
+        });
+    }
+
+    // The owner can only approve an offer made by the licensee
+    if license.status == LicenseStatus::CounterOffered {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "license id:{} is waiting for the licensee to answer a counter-offer",
+                payload.license_id
+            ),
+        });
+    }
+
+    // Record the approval, the license waits until holders of the quorum share approved
+    let mut new_license = license.clone();
+    transition_license(&mut license.clone(), LicenseStatus::Approved)?;
+    if !new_license.approvals.contains(&approver) {
+        new_license.approvals.push(approver);
+    }
+    LICENSE_STORAGE.with(|s| s.borrow_mut().insert(license.id, new_license.clone()));
+
+    if approved_bps(&holders, &new_license.approvals) < quorum_bps {
+        return Ok(new_license);
+    }
+
+    accept_latest_offer(new_license).await
+}
+
+// Helper function to add up the shares of the rights holders who approved a license
+fn approved_bps(holders: &[RightsHolder], approvals: &[OwnerId]) -> u64 {
+    holders
+        .iter()
+        .filter(|holder| approvals.contains(&holder.owner_id))
+        .map(|holder| holder.share_bps)
+        .sum()
+}
+
+// Helper function to get the managing owner who grants a right of a song,
+// composition rights are granted by the owner of the song's work
+fn right_owner_id(song: &Song, right: LicenseRight) -> Result<OwnerId, Error> {
+    match right {
+        LicenseRight::Master => Ok(song.owner_id),
+        _ => match song.work_id.and_then(|work_id| _get_work(&work_id)) {
+            Some(work) => Ok(work.owner_id),
+            None => Err(Error::InvalidPayload {
+                msg: format!("song id:{} has no work, only its master can be licensed", song.id),
+            }),
+        },
+    }
+}
+
+// Helper function to get the rights holders and approval quorum of the right a license grants,
+// the license owner holds every right of a song or work that no longer exists
+fn license_rights(license: &License) -> (Vec<RightsHolder>, u64) {
+    let song = _get_song(&license.song_id);
+    let rights = match license.right {
+        LicenseRight::Master => song.map(|song| (song.rights_holders, song.approval_quorum_bps)),
+        _ => song
+            .and_then(|song| song.work_id)
+            .and_then(|work_id| _get_work(&work_id))
+            .map(|work| (work.rights_holders, work.approval_quorum_bps)),
+    };
+
+    match rights {
+        Some(rights) => rights,
+        None => (
+            vec![RightsHolder {
+                owner_id: license.owner_id,
+                share_bps: BPS_DENOMINATOR,
+                role: RightsRole::Owner,
+            }],
+            BPS_DENOMINATOR,
+        ),
+    }
+}
+
+// Helper function to find which of the given owners the caller is
+fn caller_owner_id(owner_ids: impl IntoIterator<Item = OwnerId>) -> Option<OwnerId> {
+    let caller = ic_cdk::caller();
+    owner_ids
+        .into_iter()
+        .find(|owner_id| _get_owner(owner_id).and_then(|owner| owner.principal) == Some(caller))
+}
+
+// Define update function for the owner to counter a licensee's offer
+#[ic_cdk::update]
+async fn counter_offer_license(payload: OfferPayload) -> Result<License, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the license under negotiation
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the license
+    let owner = match _get_owner(&license.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", license.owner_id),
+            })
+        }
+    };
+
+    // Validate the authenticity of the counter-offer
+    authorize_owner(&owner, "counter-offer")?;
+
+    // Check the counter-offer is legal before any tokens move
+    add_offer(&mut license.clone(), OfferParty::Owner, payload.price, payload.terms.clone())?;
+    transition_license(&mut license.clone(), LicenseStatus::CounterOffered)?;
+
+    // The licensee's offer is declined, so its escrow goes back to them
+    let mut new_license = refund_escrow(license.id).await?;
+    transition_license(&mut new_license, LicenseStatus::CounterOffered)?;
+    add_offer(&mut new_license, OfferParty::Owner, payload.price, payload.terms)?;
+
+    // Update the license in storage and handle potential errors
+    match LICENSE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(payload.license_id, new_license.clone())
+    }) {
+        Some(_) => Ok(new_license),
+        None => Err(Error::InvalidPayload {
+            msg: format!("license id:{} could not be counter-offered", payload.license_id),
+        }),
+    }
+}
+
+// Define update function for the licensee to answer a counter-offer with a new offer
+#[ic_cdk::update]
+async fn propose_license_terms(payload: OfferPayload) -> Result<License, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // Retrieve the license under negotiation
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the licensee who filed the request
+    let licensee = match _get_licensee(&license.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found", license.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can propose new terms on their request
+    authorize_licensee(&licensee, "propose license terms")?;
+
+    // Check the new offer is legal before any tokens move
+    add_offer(&mut license.clone(), OfferParty::Licensee, payload.price, payload.terms.clone())?;
+    transition_license(&mut license.clone(), LicenseStatus::Requested)?;
+
+    // Escrow the newly offered price
+    let payment = {
+        let _guard = PaymentGuard::acquire(license.id)?;
+        let quote = quote_license_fee(payload.price).await?;
+        escrow_license_fee(license.id, ic_cdk::caller(), quote).await?
+    };
+
+    // The payment guard kept the license unchanged while the ledger was called
+    let mut new_license = match _get_license(&license.id) {
+        Some(license) => license,
+        None => {
+            let e = Error::NotFound {
+                msg: format!("license id:{} could not be found", license.id),
+            };
+            return Err(return_escrowed_fee(&license, payment, e).await);
+        }
+    };
+    let proposed = transition_license(&mut new_license, LicenseStatus::Requested)
+        .and_then(|_| add_offer(&mut new_license, OfferParty::Licensee, payload.price, payload.terms));
+    if let Err(e) = proposed {
+        return Err(return_escrowed_fee(&license, payment, e).await);
+    }
+    new_license.payments.extend(payment);
+
+    // Update the license in storage and handle potential errors
+    match LICENSE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(payload.license_id, new_license.clone())
+    }) {
+        Some(_) => Ok(new_license),
+        None => Err(Error::InvalidPayload {
+            msg: format!("license id:{} terms could not be proposed", payload.license_id),
+        }),
+    }
+}
+
+// Define update function for the licensee to accept the owner's counter-offer
+#[ic_cdk::update]
+async fn accept_counter_offer(payload: ProtectedPayload) -> Result<License, Error> {
+    // Retrieve the license under negotiation
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the licensee who filed the request
+    let licensee = match _get_licensee(&license.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found", license.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can accept a counter-offer on their request
+    authorize_licensee(&licensee, "accept counter-offers")?;
+
+    // The licensee can only accept an offer made by the owner
+    if license.status != LicenseStatus::CounterOffered {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "license id:{} has no counter-offer to accept",
+                payload.license_id
+            ),
+        });
+    }
+
+    accept_latest_offer(license).await
+}
+
+// Helper function to approve a license at the price and terms of its latest offer
+async fn accept_latest_offer(license: License) -> Result<License, Error> {
+    // Check the approval is legal before any tokens move
+    transition_license(&mut license.clone(), LicenseStatus::Approved)?;
+    check_license_conflicts(&license)?;
+    let offer = match license.offers.last() {
+        Some(offer) => offer.clone(),
+        None => {
+            return Err(Error::InvalidPayload {
+                msg: format!("license id:{} has no offer to accept", license.id),
+            })
+        }
+    };
+
+    // Escrow the accepted price unless the licensee's offer is already held
+    let payment = match held_payment(&license) {
+        Some(payment) if payment.amount == u64::from(offer.price) => None,
+        _ => {
+            let _guard = PaymentGuard::acquire(license.id)?;
+            let payer = match _get_licensee(&license.licensee_id).and_then(|l| l.principal) {
+                Some(principal) => principal,
+                None => {
+                    return Err(Error::PaymentFailed {
+                        msg: format!("licensee id:{} has no principal to pay from", license.licensee_id),
+                    })
+                }
+            };
+            let quote = quote_license_fee(offer.price).await?;
+            escrow_license_fee(license.id, payer, quote).await?
+        }
+    };
+
+    // A fee escrowed for an approval that fails from here is given back
+    let new_license = match store_approved_offer(license.id, offer, payment.clone()) {
+        Ok(new_license) => new_license,
+        Err(e) => return Err(return_escrowed_fee(&license, payment, e).await),
+    };
+
+    // Release the royalties, failed payouts stay pending for claim_license_payout
+    if held_payment(&new_license).is_some() {
+        let _ = pay_license_royalties(license.id).await;
+        return Ok(_get_license(&license.id).unwrap_or(new_license));
+    }
+
+    Ok(new_license)
+}
+
+// Helper function to approve the latest version of a license at an accepted offer once its fee is escrowed
+fn store_approved_offer(license_id: LicenseId, offer: Offer, payment: Option<LicensePayment>) -> Result<License, Error> {
+    // The payment guard kept the license unchanged while the ledger was called
+    let mut new_license = match _get_license(&license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", license_id),
+            })
+        }
+    };
+
+    // Another license may have been approved while the ledger was called
+    check_license_conflicts(&new_license)?;
+
+    // Update the owner and licensee with the approved license
+    let mut staged = StagedWrites::default();
+    match add_license_to_owner(&mut staged, new_license.owner_id, license_id) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    match add_license_to_licensee(&mut staged, new_license.licensee_id, license_id) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    // Create a new license with the approval and the agreed price
+    transition_license(&mut new_license, LicenseStatus::Approved)?;
+    new_license.price = offer.price;
+    new_license.terms = offer.terms;
+    new_license.payments.extend(payment);
+
+    // Store the approved license together with both lists
+    staged.insert_license(new_license.clone());
+    staged.commit();
+    Ok(new_license)
+}
+
+// Helper function to refuse approving a license that clashes with an approved exclusive license,
+// or that is exclusive and clashes with any approved license
+fn check_license_conflicts(license: &License) -> Result<(), Error> {
+    let conflicts: Vec<LicenseId> = licenses_on_same_rights(license)
+        .into_iter()
+        .filter(|other| other.id != license.id && other.status == LicenseStatus::Approved)
+        .filter(|other| licenses_overlap(license, other))
+        .map(|other| other.id)
+        .collect();
+
+    match conflicts.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Conflict {
+            msg: format!(
+                "license id:{} overlaps approved licenses {:?} where one of them is exclusive",
+                license.id, conflicts
+            ),
+            license_ids: conflicts,
+        }),
+    }
+}
+
+// Helper function to retrieve every license that can grant the same rights as a license:
+// those on its recording for master rights, and those on any recording of its work otherwise
+fn licenses_on_same_rights(license: &License) -> Vec<License> {
+    let work_id = match license.right {
+        LicenseRight::Master => None,
+        _ => _get_song(&license.song_id).and_then(|song| song.work_id),
+    };
+
+    match work_id {
+        Some(work_id) => index_ids(&WORK_SONG_INDEX, work_id)
+            .into_iter()
+            .flat_map(_get_song_licenses)
+            .collect(),
+        None => _get_song_licenses(license.song_id),
+    }
+}
+
+// Helper function to check whether two licenses on the same rights grant the same right
+// for overlapping dates, territories and usage types while either of them is exclusive
+fn licenses_overlap(a: &License, b: &License) -> bool {
+    let territories_overlap = match (&a.territory, &b.territory) {
+        (Territory::Worldwide, _) | (_, Territory::Worldwide) => true,
+        (Territory::Countries(a), Territory::Countries(b)) => a.iter().any(|country| b.contains(country)),
+    };
+
+    (a.exclusive || b.exclusive)
+        && a.right == b.right
+        && a.start_date < b.end_date
+        && b.start_date < a.end_date
+        && territories_overlap
+        && a.usages.iter().any(|usage| b.usages.contains(usage))
+}
+
+// Define update function for a rights holder to retry forwarding the royalties of a license fee
+#[ic_cdk::update]
+async fn claim_license_payout(payload: ProtectedPayload) -> Result<License, Error> {
+    // Retrieve the paid license
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Only the license owner and the song's rights holders can claim payouts
+    let (holders, _) = license_rights(&license);
+    let owner_ids = std::iter::once(license.owner_id)
+        .chain(holders.iter().map(|holder| holder.owner_id))
+        .chain(license.royalties.iter().map(|royalty| royalty.owner_id));
+    if caller_owner_id(owner_ids).is_none() {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "caller is not a rights holder of license id:{}, only rights holders can claim payouts",
+                payload.license_id
+            ),
+        });
+    }
+
+    pay_license_royalties(payload.license_id).await
+}
+
+// Define query function to get the ledger and fee used for license payments
+#[ic_cdk::query]
+fn get_payment_config() -> PaymentConfig {
+    PAYMENT_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Define update function to configure the ledger and fee used for license payments
+#[ic_cdk::update]
+fn set_payment_config(config: PaymentConfig) -> Result<PaymentConfig, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only a canister controller can configure payments".to_string(),
+        });
+    }
+
+    validate_payment_config(&config)?;
+
+    PAYMENT_CONFIG
+        .with(|c| c.borrow_mut().set(config.clone()))
+        .expect("Cannot set the payment config");
+    Ok(config)
+}
+
+// Helper function to check a payment config, the owners always keep part of a fee so a license can be paid out
+fn validate_payment_config(config: &PaymentConfig) -> Result<(), Error> {
+    if config.platform_fee_bps >= BPS_DENOMINATOR {
+        return Err(Error::InvalidPayload {
+            msg: format!("platform fee must be below {} basis points", BPS_DENOMINATOR),
+        });
+    }
+    Ok(())
+}
+
+// Define update function to withdraw the platform fees collected in the canister's main account
+#[ic_cdk::update]
+async fn withdraw_platform_fees(to: Account, amount: u64) -> Result<Nat, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only a canister controller can withdraw platform fees".to_string(),
+        });
+    }
+
+    let ledger = match PAYMENT_CONFIG.with(|c| c.borrow().get().ledger) {
+        Some(ledger) => ledger,
+        None => {
+            return Err(Error::PaymentFailed {
+                msg: "no ledger is configured".to_string(),
+            })
+        }
+    };
+
+    // The ledger fee is paid on top of the amount, out of the main account
+    let args = TransferArg {
+        from_subaccount: None,
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (result,): (Result<Nat, TransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,))
+            .await
+            .map_err(|(code, msg)| Error::PaymentFailed {
+                msg: format!("ledger call failed with {:?}: {}", code, msg),
+            })?;
+    result.map_err(|e| Error::PaymentFailed {
+        msg: format!("platform fees could not be withdrawn: {:?}", e),
+    })
+}
+
+// Marks a license as having a ledger call in flight until dropped
+struct PaymentGuard {
+    license_id: LicenseId,
+}
+
+impl PaymentGuard {
+    fn acquire(license_id: LicenseId) -> Result<Self, Error> {
+        match PAYMENTS_IN_PROGRESS.with(|p| p.borrow_mut().insert(license_id)) {
+            true => Ok(PaymentGuard { license_id }),
+            false => Err(Error::PaymentFailed {
+                msg: format!("license id:{} already has a payment in progress", license_id),
+            }),
+        }
+    }
+}
+
+impl Drop for PaymentGuard {
+    fn drop(&mut self) {
+        PAYMENTS_IN_PROGRESS.with(|p| p.borrow_mut().remove(&self.license_id));
+    }
+}
+
+// Helper function to check whether a license has a ledger call in flight
+fn _payment_in_progress(license_id: LicenseId) -> bool {
+    PAYMENTS_IN_PROGRESS.with(|p| p.borrow().contains(&license_id))
+}
+
+// Helper function to derive the subaccount that escrows a license's fee
+fn escrow_subaccount(license_id: LicenseId) -> Vec<u8> {
+    let mut subaccount = vec![0; 32];
+    subaccount[24..].copy_from_slice(&license_id.0.to_be_bytes());
+    subaccount
+}
+
+// Helper function to get the payment whose tokens are still held in escrow
+fn held_payment(license: &License) -> Option<&LicensePayment> {
+    license
+        .payments
+        .last()
+        .filter(|payment| payment.payout_block.is_none() && payment.refund_block.is_none())
+}
+
+// Ledger and split a license fee is escrowed with
+struct FeeQuote {
+    ledger: Principal,
+    amount: u64,
+    owner_share: u64,
+}
+
+// Helper function to check a license fee against the payment config and the ledger fee,
+// returns no quote when payments are disabled or the license is free
+async fn quote_license_fee(price: u32) -> Result<Option<FeeQuote>, Error> {
+    let config = PAYMENT_CONFIG.with(|c| c.borrow().get().clone());
+    let ledger = match config.ledger {
+        Some(ledger) if price > 0 => ledger,
+        _ => return Ok(None),
+    };
+
+    let amount = u64::from(price);
+    let fee = ledger_fee(ledger).await?;
+    let owner_share = split_license_fee(amount, config.platform_fee_bps, &fee)?;
+
+    Ok(Some(FeeQuote {
+        ledger,
+        amount,
+        owner_share,
+    }))
+}
 
-    // Create a new song based on the provided payload
-    let song = Song {
-        id,
-        title: payload.title.clone(),
-        artist: payload.artist,
-        owner_id: payload.owner_id,
-        year: payload.year,
-        genre: payload.genre,
-        price: payload.price,
+// Helper function to split a license fee into the owners' share and the platform share, returns the owners' share.
+// Each share is released with its own transfer, so each must be greater than the ledger fee
+fn split_license_fee(amount: u64, platform_fee_bps: u64, fee: &Nat) -> Result<u64, Error> {
+    let platform_share = amount * platform_fee_bps / BPS_DENOMINATOR;
+    let owner_share = amount - platform_share;
+    if *fee >= owner_share || (platform_share > 0 && *fee >= platform_share) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "price must leave the owners' share of {} and the platform share of {} each greater than the ledger fee of {}",
+                owner_share, platform_share, fee
+            ),
+        });
+    }
+    Ok(owner_share)
+}
+
+// Helper function to pull a quoted license fee from the licensee into the license's escrow subaccount,
+// returns no payment when there is nothing to pay
+async fn escrow_license_fee(
+    license_id: LicenseId,
+    payer: Principal,
+    quote: Option<FeeQuote>,
+) -> Result<Option<LicensePayment>, Error> {
+    let FeeQuote {
+        ledger,
+        amount,
+        owner_share,
+    } = match quote {
+        Some(quote) => quote,
+        None => return Ok(None),
     };
 
-    // Check if the owner exists
-    match _get_owner(&id) {
-        Some(_) => {
-            return Err(Error::InvalidPayload {
-                msg: format!("owner id:{} could not be found", id),
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: payer,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(escrow_subaccount(license_id)),
+        },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(license_id.0.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| Error::PaymentFailed {
+                msg: format!("ledger call failed with {:?}: {}", code, msg),
+            })?;
+    let collected_block = result.map_err(|e| Error::PaymentFailed {
+        msg: format!("license id:{} fee could not be escrowed: {:?}", license_id, e),
+    })?;
+
+    Ok(Some(LicensePayment {
+        ledger,
+        amount,
+        collected_block,
+        owner_share,
+        payout_block: None,
+        refund_block: None,
+        platform_block: None,
+    }))
+}
+
+// Helper function to give back a fee escrowed by a call that failed afterwards, passing its error on,
+// a fee that cannot be refunded now is recorded on the license so rejecting, cancelling or
+// timing out the request refunds it later
+async fn return_escrowed_fee(license: &License, payment: Option<LicensePayment>, error: Error) -> Error {
+    let payment = match payment {
+        Some(payment) => payment,
+        None => return error,
+    };
+
+    let refunded = match _get_licensee(&license.licensee_id).and_then(|l| l.principal) {
+        Some(recipient) => transfer_from_escrow(payment.ledger, license.id, recipient, payment.amount)
+            .await
+            .is_ok(),
+        None => false,
+    };
+    if !refunded {
+        if let Some(mut stored) = _get_license(&license.id) {
+            stored.payments.push(payment);
+            LICENSE_STORAGE.with(|s| s.borrow_mut().insert(license.id, stored));
+        }
+    }
+    error
+}
+
+// Helper function to release the rights holders' royalties from a license's escrowed fee,
+// royalties that could not be paid stay pending for the next attempt
+async fn pay_license_royalties(license_id: LicenseId) -> Result<License, Error> {
+    let _guard = PaymentGuard::acquire(license_id)?;
+
+    let license = match _get_license(&license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", license_id),
+            })
+        }
+    };
+
+    // Only approved licenses release their escrow to the rights holders
+    let payment = match held_payment(&license) {
+        Some(payment) if license.status == LicenseStatus::Approved => payment.clone(),
+        _ => {
+            return Err(Error::PaymentFailed {
+                msg: format!("license id:{} has no pending payout", license_id),
             })
         }
-        None => (),
+    };
+
+    // Split the owners' share on the first payout, by the song's current rights holders
+    let fee = ledger_fee(payment.ledger).await?;
+    let mut royalties = license.royalties.clone();
+    if royalties.is_empty() {
+        let (holders, _) = license_rights(&license);
+        royalties = allocate_royalties(license.owner_id, &holders, payment.owner_share, &fee);
     }
 
-    // Add the new song to the owner's list of songs
-    match add_song_to_owner(song.owner_id, song.id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+    let mut last_block = None;
+    let mut failure = None;
+    for royalty in royalties.iter_mut().filter(|royalty| royalty.payout_block.is_none()) {
+        let recipient = match _get_owner(&royalty.owner_id).and_then(|o| o.principal) {
+            Some(principal) => principal,
+            None => {
+                failure = Some(Error::PaymentFailed {
+                    msg: format!("owner id:{} has no principal to pay to", royalty.owner_id),
+                });
+                continue;
+            }
+        };
+
+        match transfer_from_escrow(payment.ledger, license_id, recipient, royalty.amount).await {
+            Ok(block) => {
+                royalty.payout_block = Some(block.clone());
+                last_block = Some(block);
+            }
+            Err(e) => failure = Some(e),
+        }
     }
 
-    // Store the new song in the SONG_STORAGE
-    match SONG_STORAGE.with(|s| s.borrow_mut().insert(id, song.clone())) {
-        None => Ok(song),
-        Some(_) => Err(Error::InvalidPayload {
-            msg: format!("song title:{} could not be created", payload.title),
-        }),
+    // Move the platform share to the canister's main account once every rights holder is paid,
+    // a share too small to cover the ledger fee cannot be moved and stays in escrow
+    let platform_share = payment.amount - payment.owner_share;
+    let sweep_platform_share = fee < platform_share;
+    let mut platform_block = payment.platform_block.clone();
+    if failure.is_none() && sweep_platform_share && platform_block.is_none() {
+        match transfer_from_escrow(payment.ledger, license_id, ic_cdk::id(), platform_share).await {
+            Ok(block) => {
+                platform_block = Some(block.clone());
+                last_block = Some(block);
+            }
+            Err(e) => failure = Some(e),
+        }
     }
-}
 
-// Define query functions to get owners by id
-#[ic_cdk::query]
-fn get_song_owner(id: u64) -> Result<ReturnOwner, Error> {
-    // Retrieve the song by id
-    let song = match _get_song(&id) {
-        Some(song) => song,
+    // Record the payouts on the latest version of the license
+    let mut new_license = match _get_license(&license_id) {
+        Some(license) => license,
         None => {
             return Err(Error::NotFound {
-                msg: format!("song id:{} could not be found", id),
+                msg: format!("license id:{} could not be found", license_id),
             })
         }
     };
+    new_license.royalties = royalties;
+    if let Some(payment) = new_license.payments.last_mut() {
+        payment.platform_block = platform_block.clone();
+        if new_license.royalties.iter().all(|royalty| royalty.payout_block.is_some())
+            && (platform_block.is_some() || !sweep_platform_share)
+        {
+            payment.payout_block = last_block;
+        }
+    }
+    LICENSE_STORAGE.with(|s| s.borrow_mut().insert(license_id, new_license.clone()));
 
-    // Retrieve the owner of the song
-    match _get_owner(&song.owner_id) {
-        Some(owner) => Ok(ReturnOwner {
-            id: owner.id,
-            name: owner.name,
-            email: owner.email,
-        }),
-        None => Err(Error::NotFound {
-            msg: format!("owner id:{} could not be found", song.owner_id),
-        }),
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(new_license),
     }
 }
 
-// Define query functions to search songs by title
-#[ic_cdk::query]
-fn search_song_title_genre_year(title: String) -> Result<Vec<Song>, Error> {
-    let query_title = title.to_lowercase();
-    // Retrieve all songs from the storage
-    let songs_vec: Vec<(u64, Song)> = SONG_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the songs from the tuple and create a vector
-    let songs: Vec<Song> = songs_vec.into_iter().map(|(_, song)| song).collect();
-    let mut matching_songs: Vec<Song> = Vec::new();
-
-    // Filter songs for the specified title
-    for song in songs {
-        if song.title.to_lowercase().contains(&query_title)
-            || song.year.to_string().to_lowercase().contains(&query_title)
-            || song.genre.to_lowercase().contains(&query_title)
-        {
-            matching_songs.push(song);
+// Helper function to split the owners' share of a fee between rights holders by their shares,
+// the license owner keeps rounding leftovers and any royalty too small to cover the ledger fee.
+// When what the owner keeps cannot cover the ledger fee either, it goes to the largest royalty
+fn allocate_royalties(
+    owner_id: OwnerId,
+    holders: &[RightsHolder],
+    owner_share: u64,
+    fee: &Nat,
+) -> Vec<RoyaltyPayout> {
+    let mut owner_amount = owner_share;
+    let mut royalties: Vec<RoyaltyPayout> = Vec::new();
+
+    for holder in holders.iter().filter(|holder| holder.owner_id != owner_id) {
+        let amount = owner_share * holder.share_bps / BPS_DENOMINATOR;
+        if *fee >= amount {
+            continue;
         }
+
+        owner_amount -= amount;
+        royalties.push(RoyaltyPayout {
+            owner_id: holder.owner_id,
+            amount,
+            payout_block: None,
+        });
     }
 
-    // Handle cases where no songs are found or return the result
-    match matching_songs.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no songs could be found with title:{}", title),
-        }),
-        _ => Ok(matching_songs),
+    match royalties.iter_mut().max_by_key(|royalty| royalty.amount) {
+        Some(largest) if *fee >= owner_amount => largest.amount += owner_amount,
+        _ => royalties.insert(
+            0,
+            RoyaltyPayout {
+                owner_id,
+                amount: owner_amount,
+                payout_block: None,
+            },
+        ),
     }
+    royalties
 }
 
-// Define update functions to update an existing song
-#[ic_cdk::update]
-fn update_song(payload: UpdateSongPayload) -> Result<Song, Error> {
-    // Validate Payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
+// Helper function to return a license's escrowed fee to the licensee,
+// returns the latest version of the license whether or not anything was held
+async fn refund_escrow(license_id: LicenseId) -> Result<License, Error> {
+    let _guard = PaymentGuard::acquire(license_id)?;
+
+    let license = match _get_license(&license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", license_id),
+            })
+        }
+    };
+
+    let payment = match held_payment(&license) {
+        Some(payment) => payment.clone(),
+        None => return Ok(license),
+    };
+
+    let recipient = match _get_licensee(&license.licensee_id).and_then(|l| l.principal) {
+        Some(principal) => principal,
+        None => {
+            return Err(Error::PaymentFailed {
+                msg: format!("licensee id:{} has no principal to refund", license.licensee_id),
+            })
+        }
+    };
+
+    let refund_block =
+        transfer_from_escrow(payment.ledger, license_id, recipient, payment.amount).await?;
+
+    // Record the refund on the latest version of the license
+    let mut new_license = match _get_license(&license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", license_id),
+            })
+        }
+    };
+    if let Some(payment) = new_license.payments.last_mut() {
+        payment.refund_block = Some(refund_block);
+    }
+
+    LICENSE_STORAGE.with(|s| s.borrow_mut().insert(license_id, new_license.clone()));
+    Ok(new_license)
+}
+
+// Helper function to send tokens out of a license's escrow subaccount,
+// the ledger fee is taken out of the amount
+async fn transfer_from_escrow(
+    ledger: Principal,
+    license_id: LicenseId,
+    recipient: Principal,
+    amount: u64,
+) -> Result<Nat, Error> {
+    let fee = ledger_fee(ledger).await?;
+    let amount = Nat::from(amount);
+    if amount <= fee {
+        return Err(Error::PaymentFailed {
+            msg: format!("license id:{} transfer does not cover the ledger fee", license_id),
+        });
+    }
+
+    let args = TransferArg {
+        from_subaccount: Some(escrow_subaccount(license_id)),
+        to: Account {
+            owner: recipient,
+            subaccount: None,
+        },
+        amount: amount - fee,
+        fee: None,
+        memo: Some(license_id.0.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+
+    let (result,): (Result<Nat, TransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,))
+            .await
+            .map_err(|(code, msg)| Error::PaymentFailed {
+                msg: format!("ledger call failed with {:?}: {}", code, msg),
+            })?;
+    result.map_err(|e| Error::PaymentFailed {
+        msg: format!("license id:{} escrow transfer failed: {:?}", license_id, e),
+    })
+}
+
+// Helper function to ask a ledger for its transfer fee
+async fn ledger_fee(ledger: Principal) -> Result<Nat, Error> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| Error::PaymentFailed {
+            msg: format!("ledger call failed with {:?}: {}", code, msg),
+        })?;
+    Ok(fee)
+}
+
+// Helper function to record an offer on a license under negotiation
+fn add_offer(license: &mut License, party: OfferParty, price: u32, terms: String) -> Result<(), Error> {
+    if license.offers.len() >= MAX_LICENSE_OFFERS {
         return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
+            msg: format!(
+                "license id:{} reached the limit of {} offers, accept, reject or cancel it",
+                license.id, MAX_LICENSE_OFFERS
+            ),
         });
     }
 
-    // Retrieve the existing song based on the payload
-    let song = match _get_song(&payload.id) {
-        Some(song) => song,
+    license.offers.push(Offer {
+        party,
+        price,
+        terms,
+        timestamp: ic_cdk::api::time(),
+    });
+
+    // Approvals were given to the previous offer
+    license.approvals.clear();
+    Ok(())
+}
+
+// Define update function to revoke a license
+#[ic_cdk::update]
+fn revoke_license(payload: ProtectedPayload) -> Result<License, Error> {
+    // Retrieve the license to be revoked
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
         None => {
             return Err(Error::NotFound {
-                msg: format!("song id:{} could not be found", payload.id),
+                msg: format!("license id:{} could not be found", payload.license_id),
             })
         }
     };
 
-    // Retrieve the owner of the song
-    let owner = match _get_owner(&song.owner_id) {
+    // Retrieve the owner of the license
+    let owner = match _get_owner(&license.owner_id) {
         Some(owner) => owner,
         None => {
             return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", song.owner_id),
+                msg: format!("owner id:{} could not be found", license.owner_id),
             })
         }
     };
 
-    // Check if the provided auth_key matches the owner's auth_key
-    if owner.auth_key != payload.auth_key {
-        return Err(Error::Unauthorized {
+    // Validate the authenticity of the revocation request
+    authorize_owner(&owner, "revoke")?;
+
+    // Create a new license with the revoked status
+    let mut new_license = license.clone();
+    transition_license(&mut new_license, LicenseStatus::Revoked)?;
+
+    // Remove the license from owner and licensee
+    let mut staged = StagedWrites::default();
+    match remove_license_from_owner(&mut staged, license.owner_id, license.id) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    match remove_license_from_licensee(&mut staged, license.licensee_id, license.id) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    }
+
+    // Store the revoked license together with both lists
+    staged.insert_license(new_license.clone());
+    staged.commit();
+    Ok(new_license)
+}
+
+// Define update function to reject a license request
+#[ic_cdk::update]
+async fn reject_license(payload: ProtectedPayload) -> Result<License, Error> {
+    // Retrieve the license to be rejected
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the owner of the license
+    let owner = match _get_owner(&license.owner_id) {
+        Some(owner) => owner,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("owner id:{} could not be found", license.owner_id),
+            })
+        }
+    };
+
+    // Validate the authenticity of the rejection request
+    authorize_owner(&owner, "reject")?;
+
+    // Check the rejection is legal before the escrow is refunded
+    transition_license(&mut license.clone(), LicenseStatus::Rejected)?;
+
+    // Create a new license with the rejected status
+    let mut new_license = refund_escrow(license.id).await?;
+    transition_license(&mut new_license, LicenseStatus::Rejected)?;
+
+    // Update the license in storage and handle potential errors
+    match LICENSE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(payload.license_id, new_license.clone())
+    }) {
+        Some(_) => Ok(new_license),
+        None => Err(Error::InvalidPayload {
+            msg: format!("license id:{} could not be rejected", payload.license_id),
+        }),
+    }
+}
+
+// Define update function for a licensee to cancel their license request
+#[ic_cdk::update]
+async fn cancel_license_request(payload: ProtectedPayload) -> Result<License, Error> {
+    // Retrieve the license request to be cancelled
+    let license = match _get_license(&payload.license_id) {
+        Some(license) => license,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("license id:{} could not be found", payload.license_id),
+            })
+        }
+    };
+
+    // Retrieve the licensee who filed the request
+    let licensee = match _get_licensee(&license.licensee_id) {
+        Some(licensee) => licensee,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("licensee id:{} could not be found", license.licensee_id),
+            })
+        }
+    };
+
+    // Only the licensee can cancel their own request
+    authorize_licensee(&licensee, "cancel their license requests")?;
+
+    // Check the cancellation is legal before the escrow is refunded
+    transition_license(&mut license.clone(), LicenseStatus::Cancelled)?;
+
+    // Create a new license with the cancelled status
+    let mut new_license = refund_escrow(license.id).await?;
+    transition_license(&mut new_license, LicenseStatus::Cancelled)?;
+
+    // Update the license in storage and handle potential errors
+    match LICENSE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(payload.license_id, new_license.clone())
+    }) {
+        Some(_) => Ok(new_license),
+        None => Err(Error::InvalidPayload {
+            msg: format!("license id:{} could not be cancelled", payload.license_id),
+        }),
+    }
+}
+
+// Helper function to retrieve every license requested on a song, in id order
+fn _get_song_licenses(song_id: SongId) -> Vec<License> {
+    index_ids(&SONG_LICENSE_INDEX, song_id).iter().filter_map(_get_license).collect()
+}
+
+// Helper function to retrieve a license by ID
+fn _get_license(id: &LicenseId) -> Option<License> {
+    LICENSE_STORAGE.with(|s| s.borrow().get(id))
+}
+
+// Helper function to move a license to a new status and record when it happened
+fn transition_license(license: &mut License, next: LicenseStatus) -> Result<(), Error> {
+    // A license cannot change while the ledger is settling its fee
+    if _payment_in_progress(license.id) {
+        return Err(Error::InvalidTransition {
+            msg: format!("license id:{} has a payment in progress", license.id),
+        });
+    }
+
+    if !license.status.can_transition_to(next) {
+        return Err(Error::InvalidTransition {
             msg: format!(
-                "auth key:{} is invalid, only song owner can update",
-                payload.auth_key
+                "license id:{} cannot move from {:?} to {:?}",
+                license.id, license.status, next
             ),
         });
     }
 
-    // Create a new song with updated information
-    let mut new_song = song.clone();
-    new_song.title = payload.title.clone();
-    new_song.artist = payload.artist;
-    new_song.year = payload.year;
-    new_song.genre = payload.genre;
-    new_song.price = payload.price;
+    license.status = next;
+    license.status_history.push(StatusChange {
+        status: next,
+        timestamp: ic_cdk::api::time(),
+    });
+    Ok(())
+}
+
+// Define query function to get a licensee by ID
+#[ic_cdk::query]
+fn get_licensee(id: LicenseeId) -> Result<Licensee, Error> {
+    match _get_licensee(&id) {
+        Some(licensee) => Ok(licensee),
+        None => Err(Error::NotFound {
+            msg: format!("licensee id:{} could not be found", id),
+        }),
+    }
+}
+
+// Helper function to retrieve a licensee by ID
+fn _get_licensee(id: &LicenseeId) -> Option<Licensee> {
+    LICENSEE_STORAGE.with(|s| s.borrow().get(id))
+}
+
+// Define update function to create a new licensee
+#[ic_cdk::update]
+fn create_licensee(payload: LicenseePayload) -> Result<Licensee, Error> {
+    // Validate Payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // The licensee record is bound to the calling principal
+    let caller = _get_caller()?;
 
-    // Store the updated song
-    match SONG_STORAGE.with(|s| s.borrow_mut().insert(payload.id, new_song.clone())) {
-        Some(_) => Ok(new_song),
-        None => Err(Error::InvalidPayload {
-            msg: format!(
-                "song title:{} id: {} could not be updated",
-                payload.title, payload.id
-            ),
+    // Allocate the next licensee id now that the request is valid
+    let id = LicenseeId(next_id(|sequences| &mut sequences.licensees));
+
+    // Create a new licensee instance
+    let licensee = Licensee {
+        id,
+        name: payload.name.clone(),
+        email: payload.email.clone(),
+        principal: Some(caller),
+    };
+
+    // Insert the licensee into storage and handle potential errors
+    match LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(id, licensee.clone())) {
+        None => Ok(licensee),
+        Some(_) => Err(Error::InvalidPayload {
+            msg: format!("licensee name:{} could not be created", payload.name),
         }),
     }
 }
 
-// Define update functions to delete an existing song
+// Define update function to bind a legacy licensee record to a principal
 #[ic_cdk::update]
-fn delete_song(auth_key: String, id: u64) -> Result<Song, Error> {
-    // Retrieve the existing song based on the id
-    let song = match _get_song(&id) {
-        Some(song) => song,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("song id:{} could not be found", id),
-            })
-        }
-    };
+fn bind_licensee(payload: BindLicenseePayload) -> Result<Licensee, Error> {
+    // Legacy licensees have no credential, so only a controller can bind them
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only a canister controller can bind licensees".to_string(),
+        });
+    }
 
-    // Retrieve the owner of the song
-    let owner = match _get_owner(&song.owner_id) {
-        Some(owner) => owner,
+    if payload.principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "licensees cannot be bound to the anonymous principal".to_string(),
+        });
+    }
+
+    // Retrieve the licensee to be bound
+    let mut licensee = match _get_licensee(&payload.licensee_id) {
+        Some(licensee) => licensee,
         None => {
             return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", song.owner_id),
+                msg: format!("licensee id:{} could not be found", payload.licensee_id),
             })
         }
     };
 
-    // Check if the provided auth_key matches the owner's auth_key
-    if owner.auth_key != auth_key {
+    // Licensees created by a principal cannot be rebound
+    if licensee.principal.is_some() {
         return Err(Error::InvalidPayload {
-            msg: format!(
-                "auth key:{} is invalid, only song owner can delete",
-                auth_key
-            ),
+            msg: format!("licensee id:{} is already bound", payload.licensee_id),
         });
     }
 
-    // Remove the song from owner's list
-    match remove_song_from_owner(id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
-    }
+    licensee.principal = Some(payload.principal);
 
-    // Remove the song from licensee's list
-    match remove_song_from_licensee(id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+    match LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(licensee.id, licensee.clone())) {
+        Some(_) => Ok(licensee),
+        None => Err(Error::InvalidPayload {
+            msg: format!("licensee id:{} could not be bound", payload.licensee_id),
+        }),
     }
+}
 
-    // Remove the song from the SONG_STORAGE
-    match SONG_STORAGE.with(|s| s.borrow_mut().remove(&id)) {
-        Some(song) => Ok(song),
-        None => Err(Error::InvalidPayload {
-            msg: format!("song id:{} could not be deleted", id),
+// Helper function to check that the caller is the given licensee
+fn authorize_licensee(licensee: &Licensee, action: &str) -> Result<(), Error> {
+    match licensee.principal {
+        Some(principal) if principal == ic_cdk::caller() => Ok(()),
+        Some(_) => Err(Error::Unauthorized {
+            msg: format!("caller is not licensee id:{}, only the licensee can {}", licensee.id, action),
+        }),
+        None => Err(Error::Unauthorized {
+            msg: format!("licensee id:{} is not bound to a principal yet", licensee.id),
         }),
     }
 }
 
-// Helper function to get an owner by id
-fn _get_owner(id: &u64) -> Option<Owner> {
-    OWNER_STORAGE.with(|s| s.borrow().get(id))
+// Helper function to schedule the license expiry sweeps
+fn start_expiry_sweeper() {
+    // Sweep right away to catch licenses that ended while no timer was armed
+    ic_cdk_timers::set_timer(Duration::ZERO, sweep_licenses);
+    ic_cdk_timers::set_timer_interval(EXPIRY_SWEEP_INTERVAL, sweep_licenses);
 }
 
-// Helper function to add a song to an owner's list
-fn add_song_to_owner(owner_id: u64, song_id: u64) -> Result<(), Error> {
-    // Retrieve the owner
-    let mut owner = match _get_owner(&owner_id) {
-        Some(owner) => owner,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", owner_id),
-            })
-        }
-    };
+// Helper function to run every periodic license sweep
+fn sweep_licenses() {
+    expire_licenses();
+    ic_cdk::spawn(time_out_license_requests());
+}
 
-    // Add the song id to the owner's list of song ids
-    owner.song_ids.push(song_id);
+// Helper function to expire requests the owner left unanswered, refunding their escrow
+async fn time_out_license_requests() {
+    let cutoff = ic_cdk::api::time().saturating_sub(LICENSE_REQUEST_TIMEOUT);
 
-    // Store the updated owner
-    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(owner_id, owner.clone())) {
-        Some(_) => Ok(()),
-        None => Err(Error::InvalidPayload {
-            msg: format!(
-                "song id:{} could not be added to owner id:{}",
-                song_id, owner_id
-            ),
-        }),
+    // Requests without a recorded status change predate timeouts and are left alone
+    let timed_out: Vec<LicenseId> = LICENSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, license)| license)
+            .filter(|license| license.status == LicenseStatus::Requested)
+            .filter(|license| {
+                license
+                    .status_history
+                    .last()
+                    .is_some_and(|change| change.timestamp <= cutoff)
+            })
+            .map(|license| license.id)
+            .collect()
+    });
+
+    for id in timed_out {
+        // A failed refund leaves the request for the next sweep
+        let mut license = match refund_escrow(id).await {
+            Ok(license) => license,
+            Err(_) => continue,
+        };
+
+        if transition_license(&mut license, LicenseStatus::Expired).is_ok() {
+            LICENSE_STORAGE.with(|s| s.borrow_mut().insert(id, license));
+        }
     }
 }
 
-// Define update function to create a new owner
-#[ic_cdk::update]
-fn create_owner(payload: OwnerPayload) -> Result<Owner, Error> {
-    // Validate Payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
-    }
+// Helper function to expire approved licenses whose end date has passed
+fn expire_licenses() {
+    let now = ic_cdk::api::time();
 
-    // Increment the global ID counter to get a new unique ID
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    // Identify approved licenses that have ended
+    let expired: Vec<License> = LICENSE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, license)| license)
+            .filter(|license| license.status == LicenseStatus::Approved)
+            .filter(|license| license.end_date <= now)
+            .collect()
+    });
+
+    for license in expired {
+        let mut new_license = license.clone();
+        if transition_license(&mut new_license, LicenseStatus::Expired).is_err() {
+            continue;
+        }
 
-    // Create a new owner instance
-    let owner = Owner {
-        id,
-        name: payload.name.clone(),
-        email: payload.email.clone(),
-        auth_key: payload.auth_key.clone(),
-        song_ids: Vec::new(),
-        license_ids: Vec::new(),
-    };
+        // A license missing from either list must still expire, so removal errors are ignored
+        let mut staged = StagedWrites::default();
+        let _ = remove_license_from_owner(&mut staged, license.owner_id, license.id);
+        let _ = remove_license_from_licensee(&mut staged, license.licensee_id, license.id);
 
-    // Insert the owner into the storage and handle potential errors
-    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(id, owner.clone())) {
-        None => Ok(owner),
-        Some(_) => Err(Error::InvalidPayload {
-            msg: format!("owner name:{} could not be created", payload.name),
-        }),
+        staged.insert_license(new_license);
+        staged.commit();
     }
 }
 
-// Define query function to get a license by ID
-#[ic_cdk::query]
-fn get_license(id: u64) -> Result<License, Error> {
-    match _get_license(&id) {
-        Some(license) => Ok(license),
-        None => Err(Error::NotFound {
-            msg: format!("license id:{} could not be found", id),
-        }),
-    }
-}
+// Helper function to check a license term, reporting every failing field
+fn validate_license_dates(start_date: u64, end_date: u64) -> Result<(), ValidationErrors> {
+    let now = ic_cdk::api::time();
+    let max_duration = MAX_LICENSE_DURATION.with(|d| *d.borrow().get());
+    let mut errors = ValidationErrors::new();
 
-// Define query function to get license requests for a specific owner
-#[ic_cdk::query]
-fn get_owner_license_requests(id: u64) -> Result<Vec<License>, Error> {
-    // Retrieve all licenses from storage
-    let licenses_vec: Vec<(u64, License)> = LICENSE_STORAGE.with(|s| s.borrow().iter().collect());
-    let licenses: Vec<License> = licenses_vec
-        .into_iter()
-        .map(|(_, license)| license)
-        .collect();
-    let mut owner_licenses: Vec<License> = Vec::new();
+    if start_date < now {
+        errors.add("start_date", date_error("past", "start date cannot be in the past".to_string()));
+    }
 
-    // Filter licenses for the specified owner ID
-    for license in licenses {
-        if license.owner_id == id {
-            owner_licenses.push(license);
-        }
+    if end_date <= start_date {
+        errors.add("end_date", date_error("before_start", "end date must be after the start date".to_string()));
+    } else if end_date - start_date > max_duration {
+        errors.add(
+            "end_date",
+            date_error(
+                "too_long",
+                format!("license term cannot exceed {} days", max_duration / NANOS_PER_DAY),
+            ),
+        );
     }
 
-    // Handle cases where no licenses are found or return the result
-    match owner_licenses.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no licenses could be found for owner id:{}", id),
-        }),
-        _ => Ok(owner_licenses),
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
     }
 }
 
-// Define query function to get licenses for a specific licensee
+// Helper function to build a date validation error with a readable message
+fn date_error(code: &'static str, msg: String) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(msg.into());
+    error
+}
+
+// Define query function to get the longest license term in nanoseconds
 #[ic_cdk::query]
-fn get_licensee_licenses(id: u64) -> Result<Vec<License>, Error> {
-    // Retrieve all licenses from storage
-    let licenses_vec: Vec<(u64, License)> = LICENSE_STORAGE.with(|s| s.borrow().iter().collect());
-    let licenses: Vec<License> = licenses_vec
-        .into_iter()
-        .map(|(_, license)| license)
-        .collect();
-    let mut licensee_licenses: Vec<License> = Vec::new();
+fn get_max_license_duration() -> u64 {
+    MAX_LICENSE_DURATION.with(|d| *d.borrow().get())
+}
 
-    // Filter licenses for the specified licensee ID
-    for license in licenses {
-        if license.licensee_id == id {
-            licensee_licenses.push(license);
-        }
+// Define update function to configure the longest license term in nanoseconds
+#[ic_cdk::update]
+fn set_max_license_duration(duration: u64) -> Result<u64, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only a canister controller can configure the max license duration".to_string(),
+        });
     }
 
-    // Handle cases where no licenses are found or return the result
-    match licensee_licenses.len() {
-        0 => Err(Error::NotFound {
-            msg: format!("no licenses could be found for licensee id:{}", id),
-        }),
-        _ => Ok(licensee_licenses),
+    if duration == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "max license duration must be greater than zero".to_string(),
+        });
     }
+
+    MAX_LICENSE_DURATION
+        .with(|d| d.borrow_mut().set(duration))
+        .expect("Cannot set the max license duration");
+    Ok(duration)
 }
 
-// Define update function to create a new license request
-#[ic_cdk::update]
-fn create_license_request(payload: LicensePayload) -> Result<License, Error> {
-    // Increment the global ID counter to get a new unique ID
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+// Helper function to parse "YYYY-MM-DD" or "YYYY-MM-DDTHH:MM:SSZ" into nanoseconds since the epoch
+fn parse_date(date: &str) -> Option<u64> {
+    let (day, time) = match date.trim().split_once('T') {
+        Some((day, time)) => (day, Some(time.strip_suffix('Z')?)),
+        None => (date.trim(), None),
+    };
 
-    // validate licensee
-    match _get_licensee(&payload.licensee_id) {
-        Some(_) => (),
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("licensee id:{} could not be found, add them first", payload.licensee_id),
-            })
-        }
+    // Parse and check the calendar date
+    let mut parts = day.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
     }
 
-    // Retrieve the corresponding song for the license request
-    let song = match _get_song(&payload.song_id) {
-        Some(song) => song,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("song id:{} could not be found", payload.song_id),
-            })
+    // Parse and check the time of day, dates without one start at midnight
+    let seconds = match time {
+        Some(time) => {
+            let mut parts = time.split(':');
+            let hours: i64 = parts.next()?.parse().ok()?;
+            let minutes: i64 = parts.next()?.parse().ok()?;
+            let seconds: i64 = parts.next()?.parse().ok()?;
+            if parts.next().is_some() || hours > 23 || minutes > 59 || seconds > 59 {
+                return None;
+            }
+            hours * 3_600 + minutes * 60 + seconds
         }
+        None => 0,
     };
 
-    // Create a new license instance
-    let license = License {
-        id,
-        song_id: payload.song_id,
-        owner_id: song.owner_id,
-        licensee_id: payload.licensee_id,
-        approved: false,
-        price: 0,
-        start_date: payload.start_date,
-        end_date: payload.end_date,
-    };
+    let timestamp = days_from_civil(year, month, day) * 86_400 + seconds;
+    u64::try_from(timestamp).ok()?.checked_mul(1_000_000_000)
+}
 
-    // Insert the license request into storage and handle potential errors
-    match LICENSE_STORAGE.with(|s| s.borrow_mut().insert(id, license.clone())) {
-        None => Ok(license),
-        Some(_) => Err(Error::InvalidPayload {
-            msg: format!("license id:{} could not be created", id),
-        }),
+// Helper function to count the days in a month of the proleptic Gregorian calendar
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
     }
 }
 
-// Define update function to approve a license
-#[ic_cdk::update]
-fn approve_license(payload: ApprovePayload) -> Result<License, Error> {
-    // Retrieve the license to be approved
-    let license = match _get_license(&payload.license_id) {
-        Some(license) => license,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("license id:{} could not be found", payload.license_id),
-            })
+// Helper function to count the days between 1970-01-01 and a calendar date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March so the leap day falls at the end of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Storage changes of one call, staged until every step succeeded and then written together,
+// so an error part way through leaves no dangling ids behind
+#[derive(Default)]
+struct StagedWrites {
+    // A staged None removes the record or index entry
+    songs: BTreeMap<SongId, Option<Song>>,
+    works: BTreeMap<WorkId, Option<Work>>,
+    licenses: BTreeMap<LicenseId, Option<License>>,
+    templates: BTreeMap<TemplateId, Option<OfferTemplate>>,
+    owner_songs: BTreeMap<(OwnerId, SongId), Option<()>>,
+    owner_licenses: BTreeMap<(OwnerId, LicenseId), Option<()>>,
+    licensee_licenses: BTreeMap<(LicenseeId, LicenseId), Option<()>>,
+    // Secondary index entries, staged by commit from the records staged above
+    genre_songs: BTreeMap<(IndexText, SongId), Option<()>>,
+    artist_songs: BTreeMap<(IndexText, SongId), Option<()>>,
+    year_songs: BTreeMap<(u32, SongId), Option<()>>,
+    holder_songs: BTreeMap<(OwnerId, SongId), Option<()>>,
+    holder_works: BTreeMap<(OwnerId, WorkId), Option<()>>,
+    work_songs: BTreeMap<(WorkId, SongId), Option<()>>,
+    song_licenses: BTreeMap<(SongId, LicenseId), Option<()>>,
+    licensee_requests: BTreeMap<(LicenseeId, LicenseId), Option<()>>,
+    owner_requests: BTreeMap<(OwnerId, LicenseId), Option<()>>,
+    song_templates: BTreeMap<(SongId, TemplateId), Option<()>>,
+    search_terms: BTreeMap<(IndexText, SongId), Option<u32>>,
+    // Changes to the facet counts, staged by commit from the songs staged above
+    genre_counts: BTreeMap<IndexText, i64>,
+    decade_counts: BTreeMap<u32, i64>,
+    price_band_counts: BTreeMap<u32, i64>,
+}
+
+impl StagedWrites {
+    // Check whether an owner lists a song, seeing the changes staged so far
+    fn owner_has_song(&self, owner_id: OwnerId, song_id: SongId) -> bool {
+        match self.owner_songs.get(&(owner_id, song_id)) {
+            Some(staged) => staged.is_some(),
+            None => OWNER_SONG_INDEX.with(|index| index.borrow().contains_key(&(owner_id, song_id))),
         }
-    };
+    }
 
-    // Retrieve the owner of the license
-    let owner = match _get_owner(&license.owner_id) {
-        Some(owner) => owner,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", license.owner_id),
-            })
+    // Check whether an owner lists a license, seeing the changes staged so far
+    fn owner_has_license(&self, owner_id: OwnerId, license_id: LicenseId) -> bool {
+        match self.owner_licenses.get(&(owner_id, license_id)) {
+            Some(staged) => staged.is_some(),
+            None => OWNER_LICENSE_INDEX.with(|index| index.borrow().contains_key(&(owner_id, license_id))),
         }
-    };
+    }
 
-    // Validate the authenticity of the approval request
-    if owner.auth_key != payload.auth_key {
-        return Err(Error::Unauthorized {
-            msg: format!(
-                "auth key:{} is invalid, only song owner can approve",
-                payload.auth_key
-            ),
-        });
+    // Check whether a licensee lists a license, seeing the changes staged so far
+    fn licensee_has_license(&self, licensee_id: LicenseeId, license_id: LicenseId) -> bool {
+        match self.licensee_licenses.get(&(licensee_id, license_id)) {
+            Some(staged) => staged.is_some(),
+            None => LICENSEE_LICENSE_INDEX.with(|index| index.borrow().contains_key(&(licensee_id, license_id))),
+        }
     }
 
-    // Check if the license has already been approved
-    if license.approved {
-        return Err(Error::AlreadyApproved {
-            msg: format!(
-                "license id:{} has already been approved",
-                payload.license_id
-            ),
-        });
+    fn insert_song(&mut self, song: Song) {
+        self.songs.insert(song.id, Some(song));
     }
 
-    // Create a new license with the approval and cost information
-    let mut new_license = license.clone();
-    new_license.approved = true;
-    new_license.price = payload.cost;
+    fn remove_song(&mut self, id: SongId) {
+        self.songs.insert(id, None);
+    }
 
-    // Update the owner and licensee with the approved license
-    match add_license_to_owner(license.owner_id, license.id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+    fn insert_work(&mut self, work: Work) {
+        self.works.insert(work.id, Some(work));
     }
 
-    match add_license_to_licensee(license.licensee_id, license.id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+    fn insert_license(&mut self, license: License) {
+        self.licenses.insert(license.id, Some(license));
     }
 
-    // Update the license in storage and handle potential errors
-    match LICENSE_STORAGE.with(|s| {
-        s.borrow_mut()
-            .insert(payload.license_id, new_license.clone())
-    }) {
-        Some(_) => Ok(new_license),
-        None => Err(Error::InvalidPayload {
-            msg: format!("license id:{} could not be approved", payload.license_id),
-        }),
+    fn insert_template(&mut self, template: OfferTemplate) {
+        self.templates.insert(template.id, Some(template));
     }
-}
 
-// Define update function to revoke a license
-#[ic_cdk::update]
-fn revoke_license(payload: ProtectedPayload) -> Result<License, Error> {
-    // Retrieve the license to be revoked
-    let license = match _get_license(&payload.license_id) {
-        Some(license) => license,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("license id:{} could not be found", payload.license_id),
-            })
-        }
-    };
+    fn remove_template(&mut self, id: TemplateId) {
+        self.templates.insert(id, None);
+    }
 
-    // Retrieve the owner of the license
-    let owner = match _get_owner(&license.owner_id) {
-        Some(owner) => owner,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", license.owner_id),
-            })
-        }
-    };
+    fn link_owner_song(&mut self, owner_id: OwnerId, song_id: SongId, linked: bool) {
+        self.owner_songs.insert((owner_id, song_id), linked.then_some(()));
+    }
 
-    // Validate the authenticity of the revocation request
-    if owner.auth_key != payload.auth_key {
-        return Err(Error::Unauthorized {
-            msg: format!(
-                "auth key:{} is invalid, only song owner can revoke",
-                payload.auth_key
-            ),
-        });
+    fn link_owner_license(&mut self, owner_id: OwnerId, license_id: LicenseId, linked: bool) {
+        self.owner_licenses.insert((owner_id, license_id), linked.then_some(()));
+    }
+
+    fn link_licensee_license(&mut self, licensee_id: LicenseeId, license_id: LicenseId, linked: bool) {
+        self.licensee_licenses.insert((licensee_id, license_id), linked.then_some(()));
+    }
+
+    // Write every staged change, nothing here can fail
+    fn commit(mut self) {
+        // Move the secondary index entries of every staged record from its stored version to the staged one
+        for (id, song) in self.songs.clone() {
+            let stored = _get_song(&id);
+            let (old, new) = (stored.as_ref(), song.as_ref());
+            stage_index_entries(&mut self.genre_songs, genre_song_entries, old, new);
+            stage_index_entries(&mut self.artist_songs, artist_song_entries, old, new);
+            stage_index_entries(&mut self.year_songs, year_song_entries, old, new);
+            stage_index_entries(&mut self.holder_songs, holder_song_entries, old, new);
+            stage_index_entries(&mut self.work_songs, work_song_entries, old, new);
+            stage_search_entries(&mut self.search_terms, old, new);
+            stage_facet_count(&mut self.genre_counts, genre_facet, old, new);
+            stage_facet_count(&mut self.decade_counts, decade_facet, old, new);
+            stage_facet_count(&mut self.price_band_counts, price_band_facet, old, new);
+        }
+        for (id, work) in self.works.clone() {
+            let stored = _get_work(&id);
+            stage_index_entries(&mut self.holder_works, holder_work_entries, stored.as_ref(), work.as_ref());
+        }
+        for (id, license) in self.licenses.clone() {
+            let stored = _get_license(&id);
+            let (old, new) = (stored.as_ref(), license.as_ref());
+            stage_index_entries(&mut self.song_licenses, song_license_entries, old, new);
+            stage_index_entries(&mut self.licensee_requests, licensee_request_entries, old, new);
+            stage_index_entries(&mut self.owner_requests, owner_request_entries, old, new);
+        }
+        for (id, template) in self.templates.clone() {
+            let stored = _get_offer_template(&id);
+            stage_index_entries(&mut self.song_templates, song_template_entries, stored.as_ref(), template.as_ref());
+        }
+
+        SONG_STORAGE.with(|s| apply_staged(&mut s.borrow_mut(), self.songs));
+        WORK_STORAGE.with(|s| apply_staged(&mut s.borrow_mut(), self.works));
+        LICENSE_STORAGE.with(|s| apply_staged(&mut s.borrow_mut(), self.licenses));
+        TEMPLATE_STORAGE.with(|s| apply_staged(&mut s.borrow_mut(), self.templates));
+        OWNER_SONG_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.owner_songs));
+        OWNER_LICENSE_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.owner_licenses));
+        LICENSEE_LICENSE_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.licensee_licenses));
+        GENRE_SONG_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.genre_songs));
+        ARTIST_SONG_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.artist_songs));
+        YEAR_SONG_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.year_songs));
+        HOLDER_SONG_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.holder_songs));
+        HOLDER_WORK_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.holder_works));
+        WORK_SONG_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.work_songs));
+        SONG_LICENSE_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.song_licenses));
+        LICENSEE_REQUEST_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.licensee_requests));
+        OWNER_REQUEST_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.owner_requests));
+        SONG_TEMPLATE_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.song_templates));
+        SEARCH_INDEX.with(|s| apply_staged(&mut s.borrow_mut(), self.search_terms));
+        GENRE_COUNTS.with(|s| apply_count_changes(&mut s.borrow_mut(), self.genre_counts));
+        DECADE_COUNTS.with(|s| apply_count_changes(&mut s.borrow_mut(), self.decade_counts));
+        PRICE_BAND_COUNTS.with(|s| apply_count_changes(&mut s.borrow_mut(), self.price_band_counts));
     }
+}
 
-    // Create a new license with the approval set to false
-    let mut new_license = license.clone();
-    new_license.approved = false;
+// Helper function to write staged inserts and removals into a storage map
+fn apply_staged<K: Storable + Ord + Clone, V: Storable>(
+    map: &mut StableBTreeMap<K, V, Memory>,
+    changes: BTreeMap<K, Option<V>>,
+) {
+    for (id, change) in changes {
+        match change {
+            Some(value) => map.insert(id, value),
+            None => map.remove(&id),
+        };
+    }
+}
 
-    // Remove the license from owner and licensee
-    match remove_license_from_owner(license.owner_id, license.id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+// Helper function to stage the index entries that differ between the stored and the staged version of a record,
+// None stands for a record that does not exist before or after the change
+fn stage_index_entries<T, K: Ord>(
+    changes: &mut BTreeMap<K, Option<()>>,
+    entries: fn(&T) -> Vec<K>,
+    old: Option<&T>,
+    new: Option<&T>,
+) {
+    let new_entries = new.map(entries).unwrap_or_default();
+    for key in old.map(entries).unwrap_or_default() {
+        if !new_entries.contains(&key) {
+            changes.insert(key, None);
+        }
+    }
+    for key in new_entries {
+        changes.insert(key, Some(()));
     }
+}
 
-    match remove_license_from_licensee(license.licensee_id, license.id) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
+// Helper function to stage the change a song makes to the counts of a facet, moving it from its old value to its new one
+fn stage_facet_count<K: Ord>(changes: &mut BTreeMap<K, i64>, facet: fn(&Song) -> K, old: Option<&Song>, new: Option<&Song>) {
+    if let Some(old) = old {
+        *changes.entry(facet(old)).or_insert(0) -= 1;
+    }
+    if let Some(new) = new {
+        *changes.entry(facet(new)).or_insert(0) += 1;
     }
+}
 
-    // Update the license in storage and handle potential errors
-    match LICENSE_STORAGE.with(|s| {
-        s.borrow_mut()
-            .insert(payload.license_id, new_license.clone())
-    }) {
-        Some(_) => Ok(new_license),
-        None => Err(Error::InvalidPayload {
-            msg: format!("license id:{} could not be revoked", payload.license_id),
-        }),
+// Helper function to write staged count changes, dropping the counts that reach zero
+fn apply_count_changes<K: Storable + Ord + Clone>(map: &mut StableBTreeMap<K, u64, Memory>, changes: BTreeMap<K, i64>) {
+    for (key, change) in changes {
+        let count = map.get(&key).unwrap_or_default().saturating_add_signed(change);
+        match count {
+            0 => map.remove(&key),
+            count => map.insert(key, count),
+        };
     }
 }
 
-// Helper function to retrieve a license by ID
-fn _get_license(id: &u64) -> Option<License> {
-    LICENSE_STORAGE.with(|s| s.borrow().get(id))
+// Helper function to stage the search index entries of a song, weights are written again since they can change
+fn stage_search_entries(
+    changes: &mut BTreeMap<(IndexText, SongId), Option<u32>>,
+    old: Option<&Song>,
+    new: Option<&Song>,
+) {
+    let new_entries = new.map(search_entries).unwrap_or_default();
+    for key in old.map(search_entries).unwrap_or_default().into_keys() {
+        if !new_entries.contains_key(&key) {
+            changes.insert(key, None);
+        }
+    }
+    for (key, weight) in new_entries {
+        changes.insert(key, Some(weight));
+    }
 }
 
-// Define query function to get a licensee by ID
-#[ic_cdk::query]
-fn get_licensee(id: u64) -> Result<Licensee, Error> {
-    match _get_licensee(&id) {
-        Some(licensee) => Ok(licensee),
-        None => Err(Error::NotFound {
-            msg: format!("licensee id:{} could not be found", id),
-        }),
+// Search index entries of a song, each term weighted by the fields it appears in
+fn search_entries(song: &Song) -> BTreeMap<(IndexText, SongId), u32> {
+    let fields = [
+        (song.title.as_str(), TITLE_TERM_WEIGHT),
+        (song.artist.as_str(), ARTIST_TERM_WEIGHT),
+        (song.genre.as_str(), GENRE_TERM_WEIGHT),
+        (song.isrc.as_deref().unwrap_or_default(), ISRC_TERM_WEIGHT),
+    ];
+    let mut entries = BTreeMap::new();
+    for (text, weight) in fields {
+        for term in search_terms(text) {
+            *entries.entry((IndexText::new(&term), song.id)).or_insert(0) += weight;
+        }
     }
+    for term in song.mood_tags.iter().flat_map(|tag| search_terms(tag)) {
+        *entries.entry((IndexText::new(&term), song.id)).or_insert(0) += MOOD_TERM_WEIGHT;
+    }
+    *entries.entry((IndexText::new(&song.year.to_string()), song.id)).or_insert(0) += YEAR_TERM_WEIGHT;
+    entries
 }
 
-// Helper function to retrieve a licensee by ID
-fn _get_licensee(id: &u64) -> Option<Licensee> {
-    LICENSEE_STORAGE.with(|s| s.borrow().get(id))
+// Helper function to list the search index entries the stored songs call for
+fn expected_search_entries() -> BTreeMap<(IndexText, SongId), u32> {
+    SONG_STORAGE.with(|s| s.borrow().iter().flat_map(|(_, song)| search_entries(&song)).collect())
 }
 
-// Define update function to create a new licensee
-#[ic_cdk::update]
-fn create_licensee(payload: LicenseePayload) -> Result<Licensee, Error> {
-    // Validate Payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
-    }
+// Secondary index entries of each record, one function per index
+fn owner_song_entries(song: &Song) -> Vec<(OwnerId, SongId)> {
+    vec![(song.owner_id, song.id)]
+}
 
-    // Increment the global ID counter to get a new unique ID
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+fn genre_song_entries(song: &Song) -> Vec<(IndexText, SongId)> {
+    vec![(IndexText::new(&song.genre), song.id)]
+}
 
-    // Create a new licensee instance
-    let licensee = Licensee {
-        id,
-        name: payload.name.clone(),
-        email: payload.email.clone(),
-        licenses: Vec::new(),
-    };
+fn artist_song_entries(song: &Song) -> Vec<(IndexText, SongId)> {
+    vec![(IndexText::new(&song.artist), song.id)]
+}
 
-    // Insert the licensee into storage and handle potential errors
-    match LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(id, licensee.clone())) {
-        None => Ok(licensee),
-        Some(_) => Err(Error::InvalidPayload {
-            msg: format!("licensee name:{} could not be created", payload.name),
-        }),
-    }
+fn year_song_entries(song: &Song) -> Vec<(u32, SongId)> {
+    vec![(song.year, song.id)]
 }
 
-// Helper function to add a license to an owner
-fn add_license_to_owner(owner_id: u64, license_id: u64) -> Result<(), Error> {
-    let mut owner = match _get_owner(&owner_id) {
-        Some(owner) => owner,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", owner_id),
-            })
-        }
-    };
+fn holder_song_entries(song: &Song) -> Vec<(OwnerId, SongId)> {
+    song.rights_holders.iter().map(|holder| (holder.owner_id, song.id)).collect()
+}
 
-    owner.license_ids.push(license_id);
+fn work_song_entries(song: &Song) -> Vec<(WorkId, SongId)> {
+    song.work_id.map(|work_id| (work_id, song.id)).into_iter().collect()
+}
 
-    // Insert the updated owner into storage and handle potential errors
-    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(owner_id, owner.clone())) {
-        Some(_) => Ok(()),
-        None => Err(Error::InvalidPayload {
-            msg: format!(
-                "license id:{} could not be added to owner id:{}",
-                license_id, owner_id
-            ),
-        }),
+fn holder_work_entries(work: &Work) -> Vec<(OwnerId, WorkId)> {
+    work.rights_holders.iter().map(|holder| (holder.owner_id, work.id)).collect()
+}
+
+fn song_license_entries(license: &License) -> Vec<(SongId, LicenseId)> {
+    vec![(license.song_id, license.id)]
+}
+
+fn song_template_entries(template: &OfferTemplate) -> Vec<(SongId, TemplateId)> {
+    vec![(template.song_id, template.id)]
+}
+
+fn licensee_request_entries(license: &License) -> Vec<(LicenseeId, LicenseId)> {
+    vec![(license.licensee_id, license.id)]
+}
+
+fn owner_request_entries(license: &License) -> Vec<(OwnerId, LicenseId)> {
+    vec![(license.owner_id, license.id)]
+}
+
+// Owners list their approved licenses until they expire or are revoked
+fn owner_license_entries(license: &License) -> Vec<(OwnerId, LicenseId)> {
+    match license.status {
+        LicenseStatus::Approved => vec![(license.owner_id, license.id)],
+        _ => Vec::new(),
     }
 }
 
-// Helper function to add a license to a licensee
-fn add_license_to_licensee(licensee_id: u64, license_id: u64) -> Result<(), Error> {
-    let mut licensee = match _get_licensee(&licensee_id) {
-        Some(licensee) => licensee,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("licensee id:{} could not be found", licensee_id),
-            })
-        }
-    };
+// Licensees list their approved licenses too, but stop listing them once the song is removed
+fn licensee_license_entries(license: &License) -> Vec<(LicenseeId, LicenseId)> {
+    match license.status == LicenseStatus::Approved && _get_song(&license.song_id).is_some() {
+        true => vec![(license.licensee_id, license.id)],
+        false => Vec::new(),
+    }
+}
 
-    licensee.licenses.push(license_id);
+// Helper function to read the ids listed under one key of an index map, in id order
+fn index_ids<A, B>(index: &'static LocalKey<RefCell<IndexMap<(A, B)>>>, key: A) -> Vec<B>
+where
+    A: Storable + Ord + Clone,
+    B: Storable + Ord + Clone + Default,
+{
+    index.with(|index| {
+        index
+            .borrow()
+            .range((key.clone(), B::default())..)
+            .take_while(|((entry_key, _), _)| *entry_key == key)
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
 
-    // Insert the updated licensee into storage and handle potential errors
-    match LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(licensee_id, licensee.clone())) {
-        Some(_) => Ok(()),
-        None => Err(Error::InvalidPayload {
-            msg: format!(
-                "license id:{} could not be added to licensee id:{}",
-                license_id, licensee_id
-            ),
-        }),
+// Helper function to list the index entries the stored records call for
+fn expected_entries<I, T, K>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<I, T, Memory>>>,
+    entries: fn(&T) -> Vec<K>,
+) -> BTreeMap<K, ()>
+where
+    I: Storable + Ord + Clone,
+    T: Storable,
+    K: Ord,
+{
+    storage.with(|s| {
+        s.borrow()
+            .iter()
+            .flat_map(|(_, record)| entries(&record))
+            .map(|key| (key, ()))
+            .collect()
+    })
+}
+
+// Helper function to compare an index map with the entries the stored records call for
+fn check_index<K: Storable + Ord + Clone, V: Storable + PartialEq>(
+    name: &str,
+    index: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    expected: BTreeMap<K, V>,
+) -> IndexCheck {
+    let stored: BTreeMap<K, V> = index.with(|index| index.borrow().iter().collect());
+    IndexCheck {
+        index: name.to_string(),
+        entries: stored.len() as u64,
+        missing: expected.keys().filter(|key| !stored.contains_key(key)).count() as u64,
+        stale: stored
+            .iter()
+            .filter(|(key, value)| expected.get(key) != Some(value))
+            .count() as u64,
     }
 }
 
-// Helper function to remove a license from an owner
-fn remove_license_from_owner(owner_id: u64, license_id: u64) -> Result<(), Error> {
-    let mut owner = match _get_owner(&owner_id) {
-        Some(owner) => owner,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("owner id:{} could not be found", owner_id),
-            })
+// Helper function to fill an index map that is still empty from the stored records
+fn backfill_index<K: Storable + Ord + Clone, V: Storable>(
+    index: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    expected: impl FnOnce() -> BTreeMap<K, V>,
+) {
+    if !index.with(|index| index.borrow().is_empty()) {
+        return;
+    }
+    index.with(|index| {
+        let mut index = index.borrow_mut();
+        for (key, value) in expected() {
+            index.insert(key, value);
         }
-    };
+    });
+}
 
-    // Find the index of the license ID in the owner's list
-    let mut index = 0;
-    let mut found = false;
-    for (i, id) in owner.license_ids.iter().enumerate() {
-        if *id == license_id {
-            index = i;
-            found = true;
-            break;
-        }
+// Define query function to check every secondary index against the stored records
+#[ic_cdk::query]
+fn check_indexes() -> Result<Vec<IndexCheck>, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "only a canister controller can check the indexes".to_string(),
+        });
     }
 
-    // Handle the case where the license ID is not found in the owner's list
-    if !found {
+    Ok(vec![
+        check_index("owner_songs", &OWNER_SONG_INDEX, expected_entries(&SONG_STORAGE, owner_song_entries)),
+        check_index("genre_songs", &GENRE_SONG_INDEX, expected_entries(&SONG_STORAGE, genre_song_entries)),
+        check_index("artist_songs", &ARTIST_SONG_INDEX, expected_entries(&SONG_STORAGE, artist_song_entries)),
+        check_index("year_songs", &YEAR_SONG_INDEX, expected_entries(&SONG_STORAGE, year_song_entries)),
+        check_index("holder_songs", &HOLDER_SONG_INDEX, expected_entries(&SONG_STORAGE, holder_song_entries)),
+        check_index("work_songs", &WORK_SONG_INDEX, expected_entries(&SONG_STORAGE, work_song_entries)),
+        check_index("holder_works", &HOLDER_WORK_INDEX, expected_entries(&WORK_STORAGE, holder_work_entries)),
+        check_index("song_licenses", &SONG_LICENSE_INDEX, expected_entries(&LICENSE_STORAGE, song_license_entries)),
+        check_index(
+            "licensee_requests",
+            &LICENSEE_REQUEST_INDEX,
+            expected_entries(&LICENSE_STORAGE, licensee_request_entries),
+        ),
+        check_index("owner_requests", &OWNER_REQUEST_INDEX, expected_entries(&LICENSE_STORAGE, owner_request_entries)),
+        check_index("owner_licenses", &OWNER_LICENSE_INDEX, expected_entries(&LICENSE_STORAGE, owner_license_entries)),
+        check_index(
+            "licensee_licenses",
+            &LICENSEE_LICENSE_INDEX,
+            expected_entries(&LICENSE_STORAGE, licensee_license_entries),
+        ),
+        check_index(
+            "song_templates",
+            &SONG_TEMPLATE_INDEX,
+            expected_entries(&TEMPLATE_STORAGE, song_template_entries),
+        ),
+        check_index("search_terms", &SEARCH_INDEX, expected_search_entries()),
+        check_index("genre_counts", &GENRE_COUNTS, expected_counts(genre_facet)),
+        check_index("decade_counts", &DECADE_COUNTS, expected_counts(decade_facet)),
+        check_index("price_band_counts", &PRICE_BAND_COUNTS, expected_counts(price_band_facet)),
+    ])
+}
+
+// Helper function to add a license to an owner's index
+fn add_license_to_owner(staged: &mut StagedWrites, owner_id: OwnerId, license_id: LicenseId) -> Result<(), Error> {
+    if _get_owner(&owner_id).is_none() {
         return Err(Error::NotFound {
-            msg: format!(
-                "license id:{} could not be found in owner id:{}",
-                license_id, owner_id
-            ),
+            msg: format!("owner id:{} could not be found", owner_id),
+        });
+    }
+
+    staged.link_owner_license(owner_id, license_id, true);
+    Ok(())
+}
+
+// Helper function to add a license to a licensee's index
+fn add_license_to_licensee(staged: &mut StagedWrites, licensee_id: LicenseeId, license_id: LicenseId) -> Result<(), Error> {
+    if _get_licensee(&licensee_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("licensee id:{} could not be found", licensee_id),
         });
     }
 
-    // Remove the license ID from the owner's list
-    owner.license_ids.remove(index);
+    staged.link_licensee_license(licensee_id, license_id, true);
+    Ok(())
+}
+
+// Helper function to remove a license from an owner's index
+fn remove_license_from_owner(staged: &mut StagedWrites, owner_id: OwnerId, license_id: LicenseId) -> Result<(), Error> {
+    if _get_owner(&owner_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("owner id:{} could not be found", owner_id),
+        });
+    }
 
-    // Insert the updated owner into storage and handle potential errors
-    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(owner_id, owner.clone())) {
-        Some(_) => Ok(()),
-        None => Err(Error::InvalidPayload {
+    // Check that the owner lists the license
+    if !staged.owner_has_license(owner_id, license_id) {
+        return Err(Error::NotFound {
             msg: format!(
-                "license id:{} could not be removed from owner id:{}",
+                "license id:{} could not be found in owner id:{}",
                 license_id, owner_id
             ),
-        }),
+        });
     }
-}
 
-// Helper function to remove a license from a licensee
-fn remove_license_from_licensee(licensee_id: u64, license_id: u64) -> Result<(), Error> {
-    // Retrieve the licensee to update
-    let mut licensee = match _get_licensee(&licensee_id) {
-        Some(licensee) => licensee,
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("licensee id:{} could not be found", licensee_id),
-            })
-        }
-    };
+    staged.link_owner_license(owner_id, license_id, false);
+    Ok(())
+}
 
-    // Find the index of the license ID in the licensee's list
-    let mut index = 0;
-    let mut found = false;
-    for (i, id) in licensee.licenses.iter().enumerate() {
-        if *id == license_id {
-            index = i;
-            found = true;
-            break;
-        }
+// Helper function to remove a license from a licensee's index
+fn remove_license_from_licensee(staged: &mut StagedWrites, licensee_id: LicenseeId, license_id: LicenseId) -> Result<(), Error> {
+    if _get_licensee(&licensee_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("licensee id:{} could not be found", licensee_id),
+        });
     }
 
-    // Handle the case where the license ID is not found in the licensee's list
-    if !found {
+    // Check that the licensee lists the license
+    if !staged.licensee_has_license(licensee_id, license_id) {
         return Err(Error::NotFound {
             msg: format!(
                 "license id:{} could not be found in licensee id:{}",
@@ -982,116 +5668,43 @@ fn remove_license_from_licensee(licensee_id: u64, license_id: u64) -> Result<(),
         });
     }
 
-    // Remove the license ID from the licensee's list
-    licensee.licenses.remove(index);
-
-    // Update the licensee in storage and handle potential errors
-    match LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(licensee_id, licensee.clone())) {
-        Some(_) => Ok(()),
-        None => Err(Error::InvalidPayload {
-            msg: format!(
-                "license id:{} could not be removed from licensee id:{}",
-                license_id, licensee_id
-            ),
-        }),
-    }
+    staged.link_licensee_license(licensee_id, license_id, false);
+    Ok(())
 }
 
-// Helper function to remove a song from an owner
-fn remove_song_from_owner(id: u64) -> Result<(), Error> {
-    // Retrieve the song to be removed
-    let song = _get_song(&id).ok_or(Error::NotFound {
-        msg: format!("song id:{} could not be found", id),
-    })?;
-
-    // Retrieve the owner of the song
-    let mut owner = _get_owner(&song.owner_id).ok_or(Error::NotFound {
-        msg: format!("owner id:{} could not be found", song.owner_id),
-    })?;
+// Helper function to remove a song from its owner's index
+fn remove_song_from_owner(staged: &mut StagedWrites, song: &Song) -> Result<(), Error> {
+    if _get_owner(&song.owner_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("owner id:{} could not be found", song.owner_id),
+        });
+    }
 
-    // Find the index of the song ID in the owner's list
-    let index = owner
-        .song_ids
-        .iter()
-        .position(|&x| x == song.id)
-        .ok_or(Error::NotFound {
+    // Check that the owner lists the song
+    if !staged.owner_has_song(song.owner_id, song.id) {
+        return Err(Error::NotFound {
             msg: format!(
                 "song id:{} could not be found in owner id:{}",
-                song.id, owner.id
-            ),
-        })?;
-
-    // Remove the song ID from the owner's list
-    owner.song_ids.remove(index);
-
-    // Update the owner in storage and handle potential errors
-    match OWNER_STORAGE.with(|s| s.borrow_mut().insert(owner.id, owner.clone())) {
-        Some(_) => Ok(()),
-        None => Err(Error::InvalidPayload {
-            msg: format!(
-                "song id:{} could not be removed from owner id:{}",
-                song.id, owner.id
+                song.id, song.owner_id
             ),
-        }),
+        });
     }
+
+    staged.link_owner_song(song.owner_id, song.id, false);
+    Ok(())
 }
 
 // Helper function to remove a song from all associated licensees
-fn remove_song_from_licensee(id: u64) -> Result<(), Error> {
-    // Retrieve the song to be removed
-    let song = _get_song(&id).ok_or(Error::NotFound {
-        msg: format!("song id:{} could not be found", id),
-    })?;
-
-    // Retrieve all licenses from storage
-    let licenses_vec: Vec<(u64, License)> = LICENSE_STORAGE.with(|s| s.borrow().iter().collect());
-    let licenses: Vec<License> = licenses_vec
+fn remove_song_from_licensee(staged: &mut StagedWrites, song: &Song) -> Result<(), Error> {
+    // Identify approved licenses associated with the song, only those are listed on licensees
+    let licenses_to_remove: Vec<License> = _get_song_licenses(song.id)
         .into_iter()
-        .map(|(_, license)| license)
+        .filter(|license| license.status == LicenseStatus::Approved)
         .collect();
 
-    // Identify licenses associated with the song
-    let mut licenses_to_remove: Vec<License> = Vec::new();
-    for license in licenses {
-        if license.song_id == song.id {
-            licenses_to_remove.push(license);
-        }
-    }
-
-    // Iterate over associated licenses and remove the song ID from each licensee's list
+    // Iterate over associated licenses and remove each from its licensee's list
     for license in licenses_to_remove {
-        // Retrieve the licensee to update
-        let mut licensee = _get_licensee(&license.licensee_id).ok_or(Error::NotFound {
-            msg: format!("licensee id:{} could not be found", license.licensee_id),
-        })?;
-
-        // Find the index of the license ID in the licensee's list
-        let index = licensee
-            .licenses
-            .iter()
-            .position(|&x| x == license.id)
-            .ok_or(Error::NotFound {
-                msg: format!(
-                    "license id:{} could not be found in licensee id:{}",
-                    license.id, licensee.id
-                ),
-            })?;
-
-        // Remove the license ID from the licensee's list
-        licensee.licenses.remove(index);
-
-        // Update the licensee in storage and handle potential errors
-        match LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(licensee.id, licensee.clone())) {
-            Some(_) => (),
-            None => {
-                return Err(Error::InvalidPayload {
-                    msg: format!(
-                        "license id:{} could not be removed from licensee id:{}",
-                        license.id, licensee.id
-                    ),
-                })
-            }
-        }
+        remove_license_from_licensee(staged, license.licensee_id, license.id)?;
     }
 
     Ok(())
@@ -1104,7 +5717,387 @@ enum Error {
     AlreadyApproved { msg: String },
     InvalidPayload { msg: String },
     Unauthorized { msg: String },
+    InvalidTransition { msg: String },
+    PaymentFailed { msg: String },
+    Conflict { msg: String, license_ids: Vec<LicenseId> },
+    VersionConflict { msg: String, current_version: u64 },
 }
 
 // Candid generator for Candid interface
 ic_cdk::export_candid!();
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_owner(id: u64) -> OwnerId {
+        let owner_id = OwnerId(id);
+        let owner = Owner {
+            id: owner_id,
+            name: "Blue Note Publishing".to_string(),
+            ..Default::default()
+        };
+        OWNER_STORAGE.with(|s| s.borrow_mut().insert(owner_id, owner));
+        owner_id
+    }
+
+    fn store_licensee(id: u64) -> LicenseeId {
+        let licensee_id = LicenseeId(id);
+        let licensee = Licensee {
+            id: licensee_id,
+            name: "Northern Films".to_string(),
+            ..Default::default()
+        };
+        LICENSEE_STORAGE.with(|s| s.borrow_mut().insert(licensee_id, licensee));
+        licensee_id
+    }
+
+    fn store_song(id: u64, owner_id: OwnerId) -> Song {
+        let song = Song {
+            id: SongId(id),
+            title: "Blue in Green".to_string(),
+            artist: "Miles Davis".to_string(),
+            owner_id,
+            year: 1959,
+            genre: "Jazz".to_string(),
+            version: 1,
+            ..Default::default()
+        };
+        let mut staged = StagedWrites::default();
+        staged.insert_song(song.clone());
+        staged.link_owner_song(owner_id, song.id, true);
+        staged.commit();
+        song
+    }
+
+    fn store_template(id: u64, song: &Song) -> TemplateId {
+        let template = OfferTemplate {
+            id: TemplateId(id),
+            song_id: song.id,
+            owner_id: song.owner_id,
+            right: LicenseRight::default(),
+            usages: Vec::new(),
+            media_channels: Vec::new(),
+            territory: Territory::default(),
+            exclusive: false,
+            duration: NANOS_PER_DAY,
+            price: 0,
+            terms: String::new(),
+            auto_approve: false,
+            transfer_policy: None,
+        };
+        let mut staged = StagedWrites::default();
+        staged.insert_template(template);
+        staged.commit();
+        TemplateId(id)
+    }
+
+    // Store an approved license, listed on its licensee only when asked to
+    fn store_approved_license(id: u64, song: &Song, licensee_id: LicenseeId, listed: bool) -> License {
+        let license = License {
+            id: LicenseId(id),
+            song_id: song.id,
+            owner_id: song.owner_id,
+            licensee_id,
+            status: LicenseStatus::Approved,
+            ..Default::default()
+        };
+        let mut staged = StagedWrites::default();
+        staged.insert_license(license.clone());
+        staged.link_owner_license(song.owner_id, license.id, true);
+        if listed {
+            staged.link_licensee_license(licensee_id, license.id, true);
+        }
+        staged.commit();
+        license
+    }
+
+    fn contains<K: Storable + Ord + Clone>(index: &'static LocalKey<RefCell<IndexMap<K>>>, key: K) -> bool {
+        index.with(|index| index.borrow().contains_key(&key))
+    }
+
+    fn song_search_terms(song_id: SongId) -> usize {
+        SEARCH_INDEX.with(|index| index.borrow().iter().filter(|((_, id), _)| *id == song_id).count())
+    }
+
+    #[test]
+    fn dropped_staged_writes_store_nothing() {
+        let owner_id = store_owner(1);
+        let song = store_song(2, owner_id);
+        let license = License {
+            id: LicenseId(3),
+            song_id: song.id,
+            owner_id,
+            licensee_id: LicenseeId(4),
+            ..Default::default()
+        };
+
+        // The licensee does not exist, so the second step fails after the first was staged
+        let mut staged = StagedWrites::default();
+        staged.insert_license(license.clone());
+        assert!(add_license_to_owner(&mut staged, owner_id, license.id).is_ok());
+        assert!(add_license_to_licensee(&mut staged, license.licensee_id, license.id).is_err());
+        drop(staged);
+
+        assert!(_get_license(&license.id).is_none());
+        assert!(!contains(&OWNER_LICENSE_INDEX, (owner_id, license.id)));
+        assert!(!contains(&SONG_LICENSE_INDEX, (song.id, license.id)));
+        assert!(!contains(&OWNER_REQUEST_INDEX, (owner_id, license.id)));
+    }
+
+    #[test]
+    fn approval_with_missing_licensee_changes_nothing() {
+        let owner_id = store_owner(1);
+        let song = store_song(2, owner_id);
+        let license = License {
+            id: LicenseId(3),
+            song_id: song.id,
+            owner_id,
+            licensee_id: LicenseeId(4),
+            ..Default::default()
+        };
+        let mut staged = StagedWrites::default();
+        staged.insert_license(license.clone());
+        staged.commit();
+
+        let offer = Offer {
+            party: OfferParty::Owner,
+            price: 100,
+            terms: "Worldwide sync".to_string(),
+            timestamp: 0,
+        };
+        let result = store_approved_offer(license.id, offer, None);
+
+        assert!(matches!(result, Err(Error::NotFound { .. })));
+        assert!(!contains(&OWNER_LICENSE_INDEX, (owner_id, license.id)));
+        let stored = _get_license(&license.id).unwrap();
+        assert_eq!(stored.status, LicenseStatus::Requested);
+        assert_eq!(stored.price, 0);
+    }
+
+    #[test]
+    fn failed_song_removal_changes_nothing() {
+        let owner_id = store_owner(1);
+        let song = store_song(2, owner_id);
+        let template_id = store_template(3, &song);
+        // The licensee does not list its license, so removing the song from it fails after the owner step
+        let licensee_id = store_licensee(4);
+        let license = store_approved_license(5, &song, licensee_id, false);
+
+        assert!(matches!(withdraw_song(&song), Err(Error::NotFound { .. })));
+
+        assert!(_get_song(&song.id).is_some());
+        assert!(contains(&OWNER_SONG_INDEX, (owner_id, song.id)));
+        assert!(contains(&GENRE_SONG_INDEX, (IndexText::new(&song.genre), song.id)));
+        assert!(song_search_terms(song.id) > 0);
+        assert_eq!(GENRE_COUNTS.with(|c| c.borrow().get(&IndexText::new(&song.genre))), Some(1));
+        assert!(_get_offer_template(&template_id).is_some());
+        assert!(contains(&SONG_TEMPLATE_INDEX, (song.id, template_id)));
+        assert!(contains(&SONG_LICENSE_INDEX, (song.id, license.id)));
+    }
+
+    #[test]
+    fn song_removal_removes_every_entry() {
+        let owner_id = store_owner(1);
+        let song = store_song(2, owner_id);
+        let template_id = store_template(3, &song);
+        let licensee_id = store_licensee(4);
+        let license = store_approved_license(5, &song, licensee_id, true);
+
+        assert!(withdraw_song(&song).is_ok());
+
+        assert!(_get_song(&song.id).is_none());
+        assert!(!contains(&OWNER_SONG_INDEX, (owner_id, song.id)));
+        assert!(!contains(&GENRE_SONG_INDEX, (IndexText::new(&song.genre), song.id)));
+        assert_eq!(song_search_terms(song.id), 0);
+        assert_eq!(GENRE_COUNTS.with(|c| c.borrow().get(&IndexText::new(&song.genre))), None);
+        assert!(_get_offer_template(&template_id).is_none());
+        assert!(!contains(&SONG_TEMPLATE_INDEX, (song.id, template_id)));
+        assert!(!contains(&LICENSEE_LICENSE_INDEX, (licensee_id, license.id)));
+    }
+
+    #[test]
+    fn transfer_to_missing_licensee_changes_nothing() {
+        let owner_id = store_owner(1);
+        let song = store_song(2, owner_id);
+        let licensee_id = store_licensee(3);
+        let license = store_approved_license(4, &song, licensee_id, true);
+
+        let result = complete_license_transfer(license.clone(), LicenseeId(9));
+
+        assert!(matches!(result, Err(Error::NotFound { .. })));
+        assert!(contains(&LICENSEE_LICENSE_INDEX, (licensee_id, license.id)));
+        assert!(contains(&LICENSEE_REQUEST_INDEX, (licensee_id, license.id)));
+        assert!(!contains(&LICENSEE_REQUEST_INDEX, (LicenseeId(9), license.id)));
+        let stored = _get_license(&license.id).unwrap();
+        assert_eq!(stored.licensee_id, licensee_id);
+        assert!(stored.transfers.is_empty());
+    }
+
+    #[test]
+    fn full_platform_fee_is_refused() {
+        let config = |platform_fee_bps| PaymentConfig {
+            ledger: None,
+            platform_fee_bps,
+        };
+        assert!(validate_payment_config(&config(BPS_DENOMINATOR - 1)).is_ok());
+        assert!(matches!(
+            validate_payment_config(&config(BPS_DENOMINATOR)),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn fee_shares_must_each_cover_the_ledger_fee() {
+        let fee = Nat::from(10u64);
+        assert_eq!(split_license_fee(100, 5_000, &fee).ok(), Some(50));
+        assert_eq!(split_license_fee(11, 0, &fee).ok(), Some(11));
+        // Half of 20 leaves both shares at the ledger fee
+        assert!(matches!(split_license_fee(20, 5_000, &fee), Err(Error::InvalidPayload { .. })));
+        // A small platform share could never be swept out of escrow
+        assert!(matches!(split_license_fee(100, 500, &fee), Err(Error::InvalidPayload { .. })));
+        assert!(matches!(split_license_fee(10, 0, &fee), Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn owner_amount_below_the_ledger_fee_goes_to_the_largest_royalty() {
+        let holder = |id, share_bps| RightsHolder {
+            owner_id: OwnerId(id),
+            share_bps,
+            role: RightsRole::Writer,
+        };
+        let fee = Nat::from(10u64);
+
+        // The managing owner holds no share, only the rounding leftover of 1 is theirs
+        let royalties = allocate_royalties(OwnerId(1), &[holder(2, 6_000), holder(3, 4_000)], 101, &fee);
+        let amounts: Vec<(OwnerId, u64)> = royalties.iter().map(|r| (r.owner_id, r.amount)).collect();
+        assert_eq!(amounts, vec![(OwnerId(2), 61), (OwnerId(3), 40)]);
+
+        // Holders too small to be paid leave everything with the owner
+        let royalties = allocate_royalties(OwnerId(1), &[holder(1, 9_900), holder(2, 100)], 100, &fee);
+        let amounts: Vec<(OwnerId, u64)> = royalties.iter().map(|r| (r.owner_id, r.amount)).collect();
+        assert_eq!(amounts, vec![(OwnerId(1), 100)]);
+        assert!(royalties.iter().all(|royalty| fee < royalty.amount));
+    }
+
+
+    #[test]
+    fn longest_work_title_fits_the_record_bound() {
+        let title = |chars| "\u{1F3B5}".repeat(chars);
+        let payload = |title| WorkPayload {
+            title,
+            iswc: "T-034.524.680-1".to_string(),
+            owner_id: OwnerId(1),
+            rights_holders: Vec::new(),
+            approval_quorum_bps: BPS_DENOMINATOR,
+        };
+        assert!(payload(title(MAX_BOUNDED_TEXT_CHARS as usize)).validate().is_ok());
+        assert!(payload(title(MAX_BOUNDED_TEXT_CHARS as usize + 1)).validate().is_err());
+
+        let holder = RightsHolder {
+            owner_id: OwnerId(u64::MAX),
+            share_bps: BPS_DENOMINATOR,
+            role: RightsRole::Publisher,
+        };
+        let work = Work {
+            id: WorkId(u64::MAX),
+            title: title(MAX_BOUNDED_TEXT_CHARS as usize),
+            iswc: "T0345246801".to_string(),
+            owner_id: OwnerId(u64::MAX),
+            rights_holders: vec![holder; MAX_RIGHTS_HOLDERS],
+            approval_quorum_bps: BPS_DENOMINATOR,
+        };
+        assert!(work.to_bytes().len() <= MAX_RECORD_BYTES as usize);
+    }
+
+    #[test]
+    fn exclusive_composition_license_conflicts_across_recordings_of_a_work() {
+        let owner_id = store_owner(1);
+        let recording = |id: u64| {
+            let song = Song { work_id: Some(WorkId(9)), ..store_song(id, owner_id) };
+            let mut staged = StagedWrites::default();
+            staged.insert_song(song.clone());
+            staged.commit();
+            song
+        };
+        let studio = recording(2);
+        let live = recording(3);
+        let license = |id: u64, song: &Song, right: LicenseRight| License {
+            id: LicenseId(id),
+            song_id: song.id,
+            owner_id,
+            right,
+            usages: vec![UsageType::Sync],
+            exclusive: true,
+            start_date: 0,
+            end_date: NANOS_PER_DAY,
+            ..Default::default()
+        };
+
+        let approved = License { status: LicenseStatus::Approved, ..license(4, &studio, LicenseRight::Sync) };
+        let mut staged = StagedWrites::default();
+        staged.insert_license(approved);
+        staged.commit();
+
+        // The composition is licensed through the studio recording, so the live one clashes
+        let conflict = check_license_conflicts(&license(5, &live, LicenseRight::Sync));
+        assert!(matches!(conflict, Err(Error::Conflict { license_ids, .. }) if license_ids == vec![LicenseId(4)]));
+
+        // Master rights belong to each recording, so the live one is free
+        assert!(check_license_conflicts(&license(6, &live, LicenseRight::Master)).is_ok());
+    }
+
+    #[test]
+    fn matching_page_totals_only_the_matches() {
+        let ids: Vec<u64> = (1..=10).collect();
+        let even = |id: &u64| id.is_multiple_of(2).then_some(*id);
+
+        let first = page_of_matching_ids(ids.clone(), None, 2, even);
+        assert_eq!(first.items, vec![2, 4]);
+        assert_eq!(first.next_cursor, Some(4));
+        assert_eq!(first.total, 5);
+
+        let last = page_of_matching_ids(ids, Some(8), 2, even);
+        assert_eq!(last.items, vec![10]);
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(last.total, 5);
+    }
+
+    #[test]
+    fn cut_genre_keys_only_page_songs_of_the_full_genre() {
+        let owner_id = store_owner(1);
+        let genre = |last: char| format!("{}{}", "a".repeat(MAX_INDEX_TEXT_BYTES), last);
+        for (id, last) in [(2, 'x'), (3, 'y'), (4, 'x')] {
+            let song = Song { genre: genre(last), ..store_song(id, owner_id) };
+            let mut staged = StagedWrites::default();
+            staged.insert_song(song);
+            staged.commit();
+        }
+
+        let page = page_of_text_matches(&GENRE_SONG_INDEX, &genre('x'), |song| &song.genre, None, 1);
+        assert_eq!(page.items.iter().map(|song| song.id).collect::<Vec<_>>(), vec![SongId(2)]);
+        assert_eq!(page.next_cursor, Some(SongId(2)));
+        assert_eq!(page.total, 2);
+    }
+
+    #[test]
+    fn unlisted_approved_license_is_caught_and_backfilled() {
+        let owner_id = store_owner(1);
+        let song = store_song(2, owner_id);
+        let licensee_id = store_licensee(3);
+        let license = store_approved_license(4, &song, licensee_id, false);
+        let expected = || expected_entries(&LICENSE_STORAGE, licensee_license_entries);
+
+        let check = check_index("licensee_licenses", &LICENSEE_LICENSE_INDEX, expected());
+        assert_eq!((check.entries, check.missing, check.stale), (0, 1, 0));
+        let owner_expected = expected_entries(&LICENSE_STORAGE, owner_license_entries);
+        let check = check_index("owner_licenses", &OWNER_LICENSE_INDEX, owner_expected);
+        assert_eq!((check.entries, check.missing, check.stale), (1, 0, 0));
+
+        build_secondary_indexes();
+        assert!(contains(&LICENSEE_LICENSE_INDEX, (licensee_id, license.id)));
+        let check = check_index("licensee_licenses", &LICENSEE_LICENSE_INDEX, expected());
+        assert_eq!((check.entries, check.missing, check.stale), (1, 0, 0));
+    }
+}